@@ -1,27 +1,185 @@
-use std::sync::Arc;
+use std::{
+    net::{Ipv4Addr, SocketAddrV4},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
 use anyhow::anyhow;
 use bincode::{Decode, Encode};
 use clap::Parser;
+use iroh::{NodeId, RelayMode};
 use log::LevelFilter;
 
 use phiny_core::{
     audio::{
         io::{InputDevice, OutputDevice},
-        processing::processor::{InputProcessor, OutputProcessor},
+        processing::{
+            mixer::AudioMixer,
+            processor::{InputProcessor, OutputProcessor},
+            vad::VoiceActivityDetector,
+        },
+    },
+    p2p::{
+        AccessPolicy, Call, CallConfig, CallState, Channel, Chat, ChatEvent, CloseCode, Connection,
+        ConnectionStats, ConnectionStatus, Contacts, DiscoveryMode, FileTransfer, IncomingFile,
+        Message, Peer, PeerConfig, Room, RoomConfig, RoomEvent, RoomTopology, Session,
+        SessionConfig, SessionEvent, SessionListener, Ticket, load_or_generate_secret_key,
     },
-    p2p::{Message, Peer, PeerConfig, Ticket},
 };
-use tokio::sync::Mutex;
+use tokio::{
+    io::{AsyncBufReadExt, BufReader, Lines, Stdin},
+    sync::Mutex,
+};
 /// Phiny - A simple p2p audio calling application
 #[derive(Debug, Parser)]
 struct Cli {
+    /// Name shown to the peer you are calling
+    #[clap(long, global = true)]
+    name: Option<String>,
+
+    /// Key file holding your identity, created on first use so your ticket stays the same
+    #[clap(long, global = true)]
+    identity: Option<PathBuf>,
+
+    /// Only accept calls from this node id, can be repeated
+    #[clap(long = "allow", global = true)]
+    allowed: Vec<NodeId>,
+
+    /// Call over the local network only: no relays, no internet discovery
+    #[clap(long, global = true)]
+    lan: bool,
+
+    /// UDP port to listen on, random by default
+    #[clap(long, global = true)]
+    port: Option<u16>,
+
+    /// Minutes the ticket you share stays valid
+    #[clap(long, global = true, default_value_t = 60)]
+    ticket_ttl: u64,
+
+    /// Share a ticket holding an invite and only accept calls from peers using it
+    #[clap(long, global = true)]
+    invite_only: bool,
+
+    /// How many different peers may call with the invite
+    #[clap(long, global = true, default_value_t = 1)]
+    invite_uses: u32,
+
+    /// File remembering the peers you called and which of them you verified
+    #[clap(long, global = true)]
+    contacts: Option<PathBuf>,
+
+    /// Directory where files the peer sends during a call are saved, they are declined without
+    #[clap(long, global = true)]
+    downloads: Option<PathBuf>,
+
     #[clap(subcommand)]
     commands: Commands,
 }
 
+impl Cli {
+    fn peer_config(&self) -> anyhow::Result<PeerConfig> {
+        let secret_key = match &self.identity {
+            Some(path) => Some(load_or_generate_secret_key(path)?),
+            None => None,
+        };
+        let access_policy = if self.allowed.is_empty() {
+            AccessPolicy::AllowAll
+        } else {
+            AccessPolicy::Allowlist(self.allowed.iter().copied().collect())
+        };
+        let mut config = PeerConfig {
+            secret_key,
+            access_policy,
+            require_invite: self.invite_only,
+            display_name: self.name.clone(),
+            bind_addr_v4: self
+                .port
+                .map(|port| SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port)),
+            ..PeerConfig::default()
+        };
+        if self.lan {
+            config.discovery = DiscoveryMode::LocalNetwork;
+            config.relay_mode = RelayMode::Disabled;
+        }
+        Ok(config)
+    }
+
+    async fn ticket(&self, peer: &Peer) -> anyhow::Result<Ticket> {
+        let mut ticket = Ticket::new(peer.reachable_address().await)
+            .valid_for(Duration::from_secs(self.ticket_ttl * 60));
+        if let Some(name) = &self.name {
+            ticket = ticket.with_display_name(name);
+        }
+        if self.lan {
+            ticket = ticket.direct_only();
+        }
+        if self.invite_only {
+            peer.invite(ticket, self.invite_uses)
+        } else {
+            peer.sign_ticket(ticket)
+        }
+    }
+
+    // Show the code to compare with the peer over the call; without a verified contact the peer
+    // is remembered and may be confirmed with `/verify` from the chat
+    fn show_verification_code(
+        &self,
+        connection: &Connection,
+    ) -> anyhow::Result<Option<PendingVerification>> {
+        let node_id = connection.remote_node_id();
+        let name = connection.remote_display_name().map(str::to_string);
+        let mut contacts = match &self.contacts {
+            Some(path) => Some(Contacts::load(path)?),
+            None => None,
+        };
+        if contacts
+            .as_ref()
+            .is_some_and(|contacts| contacts.is_verified(&node_id))
+        {
+            println!(
+                "✅ {} is a verified contact",
+                name.as_deref().unwrap_or("Peer")
+            );
+            return Ok(None);
+        }
+
+        let code = connection.short_auth_string();
+        println!(
+            "🔐 Compare this code with your peer: {}\n   {}",
+            code.emoji().join(" "),
+            code
+        );
+        let Some(mut contacts) = contacts.take() else {
+            return Ok(None);
+        };
+        contacts.add(node_id, name.clone());
+        contacts.save()?;
+        println!("Type /verify once your peer confirmed seeing the same code");
+        Ok(Some(PendingVerification {
+            contacts,
+            node_id,
+            name,
+        }))
+    }
+}
 
+// A peer whose code was shown, to be marked as verified when the user confirms it
+struct PendingVerification {
+    contacts: Contacts,
+    node_id: NodeId,
+    name: Option<String>,
+}
 
+impl PendingVerification {
+    fn confirm(mut self) -> anyhow::Result<()> {
+        self.contacts.mark_verified(self.node_id, self.name);
+        self.contacts.save()?;
+        println!("Marked {} as verified", self.node_id.fmt_short());
+        Ok(())
+    }
+}
 
 #[derive(Debug, Clone, clap::Subcommand)]
 enum Commands {
@@ -30,31 +188,30 @@ enum Commands {
 
     /// Call the peer using the ticket
     Connect { ticket: String },
+
+    /// Start a group call, or join the one of a room ticket
+    Room {
+        ticket: Option<String>,
+
+        /// Relay the audio of every member instead of having everyone send it to everyone,
+        /// when starting a room
+        #[clap(long)]
+        forward: bool,
+
+        /// Do not send audio while you are not speaking
+        #[clap(long)]
+        drop_silent: bool,
+    },
 }
 
-#[derive(Debug, Encode, Decode)]
+#[derive(Debug, Clone, Encode, Decode)]
 struct AudioFrame {
     data: Vec<u8>,
 }
 
 impl Message for AudioFrame {
-    fn deserialize(data: &[u8]) -> anyhow::Result<Self>
-    where
-        Self: Sized,
-    {
-        let deserialized_data = bincode::decode_from_slice(data, bincode::config::standard())?;
-        return Ok(deserialized_data.0);
-    }
-    fn serialize(&self) -> anyhow::Result<Vec<u8>> {
-        let serialized_data = bincode::encode_to_vec(self, bincode::config::standard())?;
-        return Ok(serialized_data);
-    }
-}
-
-#[derive(Debug, Decode, Encode)]
-struct TextMessage(String);
+    const CHANNEL: Channel = Channel::application(0);
 
-impl Message for TextMessage {
     fn deserialize(data: &[u8]) -> anyhow::Result<Self>
     where
         Self: Sized,
@@ -99,21 +256,17 @@ async fn test_p2p_data_transfer() -> anyhow::Result<()> {
         Commands::Connect { ticket } => {
             let peer = Peer::new(PeerConfig::default()).await?;
             let ticket = Ticket::decode(&ticket)?;
-            let mut connection = peer.connect(ticket.node_addrs.clone()).await?;
+            let connection = peer.connect(ticket.node_addrs.clone()).await?;
 
             println!("Connected to peer {}", ticket.node_addrs.node_id);
-            let text_message: Option<TextMessage> = connection.receive().await?;
-            match text_message {
-                None => {}
-                Some(msg) => {
-                    println!("Received from listener:{}", msg.0);
-                }
+            let chat = Chat::open(Arc::new(connection));
+            if let Some(ChatEvent::Received(msg)) = chat.recv().await {
+                println!("Received from listener:{}", msg.text);
             }
-            connection
-                .send(TextMessage("I am connector".to_string()))
-                .await?;
+            chat.send("I am connector").await?;
             tokio::signal::ctrl_c().await?;
         }
+        Commands::Room { .. } => anyhow::bail!("Rooms are not part of this test"),
         Commands::Listen => {
             let peer = Peer::new(PeerConfig::default()).await?;
             let mut listener = peer.listen().await?;
@@ -124,20 +277,23 @@ async fn test_p2p_data_transfer() -> anyhow::Result<()> {
                 self_ticket.encode()?
             );
 
-            if let Some(mut connection) = listener.accept().await? {
+            if let Some(connection) = listener.accept().await? {
                 println!("Peer connected!");
-                connection
-                    .send(TextMessage("I am listener".to_string()))
-                    .await?;
+                let chat = Chat::open(Arc::new(connection));
+                chat.send("I am listener").await?;
                 println!("Sent the message");
 
-                let text_message: Option<TextMessage> = connection.receive().await?;
-                match text_message {
-                    None => {
-                        println!("Received none");
-                    }
-                    Some(msg) => {
-                        println!("Received from connector:{}", msg.0);
+                loop {
+                    match chat.recv().await {
+                        None => {
+                            println!("Received none");
+                            break;
+                        }
+                        Some(ChatEvent::Received(msg)) => {
+                            println!("Received from connector:{}", msg.text);
+                            break;
+                        }
+                        Some(ChatEvent::Delivered(_)) => println!("Message delivered"),
                     }
                 }
             }
@@ -151,13 +307,43 @@ async fn test_p2p_data_transfer() -> anyhow::Result<()> {
 async fn test_listener_and_connector() -> anyhow::Result<()> {
     let cli = Cli::parse();
 
-    match cli.commands {
+    let config = cli.peer_config()?;
+
+    match cli.commands.clone() {
         Commands::Connect { ticket } => {
-            let peer = Peer::new(PeerConfig::default()).await?;
             let ticket = Ticket::decode(&ticket)?;
-            let connection = peer.connect(ticket.node_addrs.clone()).await?;
+            ticket.validate()?;
+            if let Some(name) = &ticket.display_name {
+                println!("Calling {}...", name);
+            }
+
+            let peer = Peer::new(config).await?;
+            let session = Arc::new(
+                Session::connect_ticket(&peer, ticket.clone(), SessionConfig::default()).await?,
+            );
+
+            println!(
+                "Connected to peer {} ({})",
+                ticket.node_addrs.node_id,
+                session
+                    .connection()
+                    .remote_display_name()
+                    .unwrap_or("unnamed")
+            );
+            tokio::spawn(print_session_events(session.events()));
+
+            let call = Call::dial_session(Arc::clone(&session), CallConfig::default()).await?;
+            println!("📞 Calling...");
+            call.established().await?;
+            println!("Call accepted! Type a line to chat, or /send <file> to send a file");
+            let verification = cli.show_verification_code(&session.connection())?;
+            let chat = Chat::open_session(Arc::clone(&session));
+            let remote_name = session
+                .connection()
+                .remote_display_name()
+                .unwrap_or("peer")
+                .to_string();
 
-            println!("Connected to peer {}", ticket.node_addrs.node_id);
             let input_device = Arc::new(Mutex::new(InputDevice::new()?));
             let mut processor = InputProcessor::new(48000, 1)?;
 
@@ -166,30 +352,60 @@ async fn test_listener_and_connector() -> anyhow::Result<()> {
                 return Err(anyhow!("Input init error: {}", e));
             }
 
-            while let Some(data) = input_device.receive().await {
-                match processor.process_stream(&data) {
-                    Ok(processed_data) => {
-                        if let Err(e) = connection
-                            .send(AudioFrame {
+            let capture = async {
+                while let Some(data) = input_device.receive().await {
+                    match processor.process_stream(&data) {
+                        Ok(processed_data) => {
+                            // Frames captured while the session reconnects are dropped
+                            if session.is_closed() {
+                                break;
+                            }
+                            let _ = session.send_unreliable(AudioFrame {
                                 data: processed_data,
-                            })
-                            .await
-                        {
-                            eprintln!("Send error: {}", e);
-                            break;
+                            });
                         }
+                        Err(e) => eprintln!("Processing error: {}", e),
                     }
-                    Err(e) => eprintln!("Processing error: {}", e),
                 }
+            };
+
+            let mut stdin = BufReader::new(tokio::io::stdin()).lines();
+            tokio::select! {
+                _ = capture => {}
+                _ = run_chat(&chat, &session, &remote_name, &mut stdin, verification) => {}
+                _ = receive_files(&session, cli.downloads.as_deref()) => {}
+                end = call.ended() => println!("Call ended: {}", end),
+                _ = tokio::signal::ctrl_c() => call.hangup().await?,
             }
 
-            tokio::signal::ctrl_c().await?;
+            print_stats(&session.connection().stats());
+            if let Err(e) = session.close(CloseCode::Normal, "call ended").await {
+                eprintln!("Close error: {}", e);
+            }
+        }
+
+        Commands::Room {
+            ticket,
+            forward,
+            drop_silent,
+        } => {
+            let room_config = RoomConfig {
+                topology: if forward {
+                    RoomTopology::Forwarded
+                } else {
+                    RoomTopology::Mesh
+                },
+                drop_silent,
+                ..RoomConfig::default()
+            };
+            run_room(config, room_config, ticket).await?
         }
 
         Commands::Listen => {
-            let peer = Peer::new(PeerConfig::default()).await?;
-            let mut listener = peer.listen().await?;
-            let self_ticket = Ticket::new(peer.address());
+            let peer = Peer::new(config).await?;
+            let listener = peer.listen().await?;
+            let mut listener = SessionListener::new(listener, SessionConfig::default());
+            let self_ticket = cli.ticket(&peer).await?;
 
             println!(
                 "🎟️ Share this ticket with your peer:\n{}",
@@ -198,11 +414,49 @@ async fn test_listener_and_connector() -> anyhow::Result<()> {
 
             let output_device = Arc::new(Mutex::new(OutputDevice::new()?));
 
-            if let Some(mut connection) = listener.accept().await? {
-                println!("Peer connected!");
+            if let Some(session) = listener.accept().await? {
+                let session = Arc::new(session);
+                let remote_name = session
+                    .connection()
+                    .remote_display_name()
+                    .unwrap_or("unnamed")
+                    .to_string();
+                let remote_node_id = session.connection().remote_node_id();
+                println!("Peer connected! ({}, {})", remote_name, remote_node_id);
+                tokio::spawn(print_session_events(session.events()));
+
+                let call =
+                    Call::answer_session(Arc::clone(&session), CallConfig::default()).await?;
+                println!(
+                    "📞 Incoming call from {} ({}), accept? [y/N]",
+                    remote_name,
+                    remote_node_id.fmt_short()
+                );
+
+                let mut stdin = BufReader::new(tokio::io::stdin()).lines();
+                tokio::select! {
+                    line = stdin.next_line() => {
+                        if line?.is_some_and(|answer| answer.trim().eq_ignore_ascii_case("y")) {
+                            call.accept().await?;
+                        } else {
+                            call.reject("declined").await?;
+                        }
+                    }
+                    end = call.ended() => println!("Call ended: {}", end),
+                }
+                if call.state() != CallState::Active {
+                    if let Err(e) = session.close(CloseCode::Normal, "call declined").await {
+                        eprintln!("Close error: {}", e);
+                    }
+                    return Ok(());
+                }
 
+                println!("Type a line to chat, or /send <file> to send a file");
+                let verification = cli.show_verification_code(&session.connection())?;
+                let chat = Chat::open_session(Arc::clone(&session));
                 let mut processor = OutputProcessor::new(48000, 1)?;
                 let output_device = Arc::clone(&output_device);
+                let playback_session = Arc::clone(&session);
 
                 tokio::spawn(async move {
                     let mut output_device = output_device.lock().await;
@@ -211,9 +465,25 @@ async fn test_listener_and_connector() -> anyhow::Result<()> {
                         return;
                     }
 
-                    while let Ok(Some(bytes)) = connection.receive::<AudioFrame>().await {
+                    let mut events = playback_session.events();
+                    loop {
+                        let bytes = tokio::select! {
+                            frame = playback_session.receive_unreliable::<AudioFrame>() => match frame {
+                                Ok(Some(bytes)) => bytes,
+                                _ => break,
+                            },
+                            Ok(SessionEvent::Resumed) = events.recv() => {
+                                // Start over with a fresh decoder after the interruption
+                                if let Err(e) = processor.reset() {
+                                    eprintln!("Processing error: {}", e);
+                                }
+                                continue;
+                            }
+                        };
                         println!("Get processed data to output device");
                         match processor.process_stream(&bytes.data) {
+                            // Late frame which was dropped by the processor
+                            Ok(processed) if processed.is_empty() => {}
                             Ok(processed) => {
                                 if let Err(e) = output_device.send(processed).await {
                                     eprintln!("Output send error: {}", e);
@@ -225,10 +495,266 @@ async fn test_listener_and_connector() -> anyhow::Result<()> {
                     }
                 });
 
-                tokio::signal::ctrl_c().await?;
+                tokio::select! {
+                    _ = run_chat(&chat, &session, &remote_name, &mut stdin, verification) => {}
+                    _ = receive_files(&session, cli.downloads.as_deref()) => {}
+                    end = call.ended() => println!("Call ended: {}", end),
+                    _ = tokio::signal::ctrl_c() => call.hangup().await?,
+                }
+
+                print_stats(&session.connection().stats());
+                if let Err(e) = session.close(CloseCode::Normal, "call ended").await {
+                    eprintln!("Close error: {}", e);
+                }
             }
+            listener.close().await;
         }
     }
 
     Ok(())
 }
+
+// Group call: everyone's audio is sent to every member and the members' audio is mixed
+async fn run_room(
+    mut config: PeerConfig,
+    room_config: RoomConfig,
+    ticket: Option<String>,
+) -> anyhow::Result<()> {
+    // Members find each other on the room's gossip topic
+    config.gossip = true;
+    let peer = Peer::new(config).await?;
+    let listener = peer.listen().await?;
+    let room = match ticket {
+        Some(ticket) => {
+            let ticket = Ticket::decode(&ticket)?;
+            ticket.validate()?;
+            Room::join(&peer, listener, &ticket, room_config).await?
+        }
+        // The room is named after the peer which opened it
+        None => {
+            let id = peer.node_id().fmt_short().to_string();
+            Room::create(&peer, listener, id, room_config).await?
+        }
+    };
+    println!(
+        "🎟️ Share this ticket to invite others to the room:\n{}",
+        room.invite_ticket().encode()?
+    );
+    for member in room.members() {
+        println!(
+            "👋 {} ({}) is in the room",
+            member.display_name.as_deref().unwrap_or("unnamed"),
+            member.node_id.fmt_short()
+        );
+    }
+
+    let mut input_device = InputDevice::new()?;
+    if let Err(e) = input_device.init() {
+        return Err(anyhow!("Input init error: {}", e));
+    }
+    let mut output_device = OutputDevice::new()?;
+    if let Err(e) = output_device.init() {
+        return Err(anyhow!("Output init error: {}", e));
+    }
+    let mut processor = InputProcessor::new(48000, 1)?;
+    let mut vad = VoiceActivityDetector::default();
+    let mut mixer = AudioMixer::new(48000, 1);
+    let mut frames = room.unreliable_receiver::<AudioFrame>();
+    let mut events = room.events();
+
+    let capture = async {
+        while let Some(data) = input_device.receive().await {
+            match processor.process_stream(&data) {
+                Ok(processed_data) => {
+                    let speaking = vad.is_speech(&data);
+                    let _ = room.send_audio(
+                        AudioFrame {
+                            data: processed_data,
+                        },
+                        speaking,
+                    );
+                }
+                Err(e) => eprintln!("Processing error: {}", e),
+            }
+        }
+    };
+
+    // Every 20 ms, play one frame of everyone mixed together
+    let playback = async {
+        let mut tick = tokio::time::interval(Duration::from_millis(20));
+        loop {
+            tokio::select! {
+                Some((node_id, frame)) = frames.recv() => {
+                    if let Err(e) = mixer.push(node_id, &frame.data) {
+                        eprintln!("Processing error: {}", e);
+                    }
+                }
+                Ok(event) = events.recv() => match event {
+                    RoomEvent::Joined(member) => println!(
+                        "👋 {} ({}) joined",
+                        member.display_name.as_deref().unwrap_or("unnamed"),
+                        member.node_id.fmt_short()
+                    ),
+                    RoomEvent::Left { node_id, reason } => {
+                        println!("🚪 {} left: {}", node_id.fmt_short(), reason);
+                        mixer.remove(&node_id);
+                    }
+                },
+                _ = tick.tick() => {
+                    if let Err(e) = output_device.send(mixer.mix(960)).await {
+                        eprintln!("Output send error: {}", e);
+                        break;
+                    }
+                }
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = capture => {}
+        _ = playback => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+    room.leave().await;
+    Ok(())
+}
+
+// Send the lines typed during the call as chat messages and print the ones of the peer, until
+// the chat is gone
+async fn run_chat(
+    chat: &Chat,
+    session: &Session,
+    remote_name: &str,
+    stdin: &mut Lines<BufReader<Stdin>>,
+    mut verification: Option<PendingVerification>,
+) {
+    let mut typing = true;
+    loop {
+        tokio::select! {
+            line = stdin.next_line(), if typing => match line {
+                Ok(Some(line)) if line.trim().is_empty() => {}
+                Ok(Some(line)) if line.trim() == "/verify" => match verification.take() {
+                    Some(verification) => {
+                        if let Err(e) = verification.confirm() {
+                            eprintln!("Failed to save the contact: {}", e);
+                        }
+                    }
+                    None => println!("Nothing to verify"),
+                },
+                Ok(Some(line)) if line.starts_with("/send ") => {
+                    let path = PathBuf::from(line["/send ".len()..].trim());
+                    tokio::spawn(send_file(session.connection(), path));
+                }
+                Ok(Some(line)) => {
+                    if let Err(e) = chat.send(line).await {
+                        eprintln!("Chat error: {}", e);
+                    }
+                }
+                // Keep showing the peer's messages without a terminal to type in
+                _ => typing = false,
+            },
+            event = chat.recv() => match event {
+                Some(ChatEvent::Received(message)) => {
+                    println!("💬 {}: {}", remote_name, message.text)
+                }
+                Some(ChatEvent::Delivered(id)) => println!("✓ Message {} delivered", id),
+                None => break,
+            },
+        }
+    }
+}
+
+async fn send_file(connection: Arc<Connection>, path: PathBuf) {
+    let transfer = match FileTransfer::send(&connection, &path).await {
+        Ok(transfer) => transfer,
+        Err(e) => {
+            eprintln!("Failed to send {}: {}", path.display(), e);
+            return;
+        }
+    };
+    println!(
+        "📤 Sending {} ({} bytes)",
+        transfer.offer().name,
+        transfer.offer().size
+    );
+    match transfer.finished().await {
+        Ok(path) => println!("✅ Sent {}", path.display()),
+        Err(e) => eprintln!("Failed to send {}: {}", path.display(), e),
+    }
+}
+
+// Save the files the peer sends into `downloads`, or decline them without a directory
+async fn receive_files(session: &Session, downloads: Option<&Path>) {
+    let mut connections = session.watch_connection();
+    loop {
+        let connection = connections.borrow_and_update().clone();
+        let incoming = match IncomingFile::receive(&connection).await {
+            Ok(incoming) => incoming,
+            Err(e) => {
+                // A lost connection is not always marked as such yet, give it a moment
+                let mut status = connection.watch_status();
+                let lost = tokio::time::timeout(
+                    Duration::from_secs(1),
+                    status.wait_for(ConnectionStatus::is_terminal),
+                )
+                .await
+                .is_ok();
+                if !lost {
+                    eprintln!("Ignoring a file offer: {}", e);
+                    continue;
+                }
+                // Start over on the next connection once the session resumes
+                match connections.changed().await {
+                    Ok(()) => continue,
+                    Err(_) => return,
+                }
+            }
+        };
+        let offer = incoming.offer().clone();
+        let Some(downloads) = downloads else {
+            println!(
+                "📥 Declined {} ({} bytes), start with --downloads to receive files",
+                offer.name, offer.size
+            );
+            let _ = incoming.reject("not accepting files").await;
+            continue;
+        };
+        println!("📥 Receiving {} ({} bytes)", offer.name, offer.size);
+        match incoming.accept(downloads).await {
+            Ok(transfer) => {
+                tokio::spawn(async move {
+                    match transfer.finished().await {
+                        Ok(path) => println!("✅ Saved {}", path.display()),
+                        Err(e) => eprintln!("Failed to receive {}: {}", offer.name, e),
+                    }
+                });
+            }
+            Err(e) => eprintln!("Failed to receive {}: {}", offer.name, e),
+        }
+    }
+}
+
+async fn print_session_events(mut events: tokio::sync::broadcast::Receiver<SessionEvent>) {
+    while let Ok(event) = events.recv().await {
+        match event {
+            SessionEvent::Disconnected { reason } => println!("⚠️ Connection lost: {}", reason),
+            SessionEvent::Reconnecting { attempt, delay } => {
+                println!("Reconnecting (attempt {}) in {:?}...", attempt, delay)
+            }
+            SessionEvent::Resumed => println!("✅ Reconnected"),
+            SessionEvent::Closed { .. } => break,
+        }
+    }
+}
+
+fn print_stats(stats: &ConnectionStats) {
+    println!(
+        "📊 Path: {}, RTT: {:?}, packet loss: {:.1}%, audio frames sent/received: {}/{} ({} dropped)",
+        stats.connection_type,
+        stats.rtt,
+        stats.loss_rate() * 100.0,
+        stats.datagrams_sent,
+        stats.datagrams_received,
+        stats.dropped_messages
+    );
+}
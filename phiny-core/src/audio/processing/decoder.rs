@@ -1,4 +1,5 @@
 use anyhow::Context as _;
+use audiopus::coder::GenericCtl as _;
 
 pub struct Decoder {
     decoder_internal: audiopus::coder::Decoder,
@@ -25,4 +26,19 @@ impl Decoder {
             .decode(Some(data), &mut decoded, false)?;
         Ok(decoded[..decoded_data].to_vec())
     }
+
+    // Synthesizes a frame in place of one that was lost, using opus packet loss concealment
+    pub fn conceal(&mut self) -> anyhow::Result<Vec<i16>> {
+        let mut concealed = vec![0i16; 960];
+        let concealed_data = self
+            .decoder_internal
+            .decode(None::<&[u8]>, &mut concealed, false)?;
+        Ok(concealed[..concealed_data].to_vec())
+    }
+
+    // Drops the decoder state, e.g. after the stream was interrupted
+    pub fn reset(&mut self) -> anyhow::Result<()> {
+        self.decoder_internal.reset_state()?;
+        Ok(())
+    }
 }
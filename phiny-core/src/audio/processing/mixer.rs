@@ -0,0 +1,85 @@
+use std::{
+    collections::{HashMap, VecDeque, hash_map::Entry},
+    hash::Hash,
+};
+
+use super::processor::OutputProcessor;
+
+// Beyond this much buffered audio per participant the oldest samples are dropped, so a
+// participant whose frames arrive in bursts does not drift behind the others
+const MAX_BUFFERED_MS: usize = 200;
+
+struct Participant {
+    processor: OutputProcessor,
+    samples: VecDeque<f32>,
+}
+
+/// Decodes the audio of several participants and mixes it into a single stream
+///
+/// Every participant gets its own [`OutputProcessor`], so loss concealment and sequence
+/// numbers are tracked per participant. `K` identifies a participant, e.g. its node id.
+pub struct AudioMixer<K> {
+    sample_rate: u32,
+    channels: u16,
+    participants: HashMap<K, Participant>,
+}
+
+impl<K: Hash + Eq> AudioMixer<K> {
+    pub fn new(sample_rate: u32, channels: u16) -> Self {
+        Self {
+            sample_rate,
+            channels,
+            participants: HashMap::new(),
+        }
+    }
+
+    /// Decode an encoded frame of `participant` and queue it for mixing
+    pub fn push(&mut self, participant: K, data: &[u8]) -> anyhow::Result<()> {
+        let max_buffered =
+            self.sample_rate as usize * self.channels as usize * MAX_BUFFERED_MS / 1000;
+        let participant = match self.participants.entry(participant) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(Participant {
+                processor: OutputProcessor::new(self.sample_rate, self.channels)?,
+                samples: VecDeque::new(),
+            }),
+        };
+
+        participant
+            .samples
+            .extend(participant.processor.process_stream(data)?);
+        let excess = participant.samples.len().saturating_sub(max_buffered);
+        participant.samples.drain(..excess);
+        Ok(())
+    }
+
+    /// Forget a participant, e.g. once it left the call
+    pub fn remove(&mut self, participant: &K) {
+        self.participants.remove(participant);
+    }
+
+    /// Start over with a fresh decoder for `participant` after its stream was interrupted
+    pub fn reset(&mut self, participant: &K) -> anyhow::Result<()> {
+        if let Some(participant) = self.participants.get_mut(participant) {
+            participant.processor.reset()?;
+            participant.samples.clear();
+        }
+        Ok(())
+    }
+
+    /// Take `len` samples of every participant and sum them, participants with nothing
+    /// buffered are silent
+    pub fn mix(&mut self, len: usize) -> Vec<f32> {
+        let mut mixed = vec![0.0; len];
+        for participant in self.participants.values_mut() {
+            let available = participant.samples.len().min(len);
+            for (out, sample) in mixed.iter_mut().zip(participant.samples.drain(..available)) {
+                *out += sample;
+            }
+        }
+        for sample in &mut mixed {
+            *sample = sample.clamp(-1.0, 1.0);
+        }
+        mixed
+    }
+}
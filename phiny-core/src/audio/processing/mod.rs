@@ -1,4 +1,6 @@
 mod decoder;
 mod encoder;
 mod jitter_buffer;
+pub mod mixer;
 pub mod processor;
+pub mod vad;
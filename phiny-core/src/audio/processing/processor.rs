@@ -56,8 +56,15 @@ pub struct InputProcessor {
 }
 pub struct OutputProcessor {
     decoder: decoder::Decoder,
+    last_sequence_number: Option<u32>,
 }
 
+// Upper bound of the lost frames we synthesize on a gap, the rest of a longer gap is skipped
+const MAX_CONCEALED_FRAMES: u32 = 5;
+// A frame this far behind the last one is not late, the sender started a new stream (e.g. a
+// new encoder after the session resumed)
+const MAX_LATE_FRAMES: u32 = 50;
+
 impl InputProcessor {
     pub fn new(sample_rate: u32, channels: u16) -> anyhow::Result<Self> {
         let encoder = encoder::Encoder::new(sample_rate, channels)?;
@@ -84,14 +91,45 @@ impl InputProcessor {
 impl OutputProcessor {
     pub fn new(sample_rate: u32, channels: u16) -> anyhow::Result<Self> {
         let decoder = decoder::Decoder::new(sample_rate, channels)?;
-        return Ok(Self { decoder: decoder });
+        Ok(Self {
+            decoder,
+            last_sequence_number: None,
+        })
     }
 
+    // Frames arrive over an unreliable transport, so they can be lost or late.
+    // Late frames (older than what we already played) are dropped and returns empty samples,
+    // lost frames are concealed by the decoder before the current frame is decoded. A frame far
+    // behind the last one starts the stream over, as after `reset`.
     pub fn process_stream(&mut self, data: &[u8]) -> anyhow::Result<Vec<f32>> {
         // This function expect to get the full encoded data for decoding
         let audio_frame_decoded = AudioFrame::decode(data)?;
-        let decoded_data = self.decoder.decode(&audio_frame_decoded.samples)?;
-        let f32_converted_data = convert_i16_sample_to_f32(&decoded_data);
-        return Ok(f32_converted_data);
+        let sequence_number = audio_frame_decoded.sequence_number;
+
+        let mut decoded_data = Vec::new();
+        if let Some(last_sequence_number) = self.last_sequence_number {
+            if last_sequence_number.saturating_sub(sequence_number) > MAX_LATE_FRAMES {
+                self.decoder.reset()?;
+            } else if sequence_number <= last_sequence_number {
+                return Ok(Vec::new());
+            } else {
+                let lost_frames = sequence_number - last_sequence_number - 1;
+                for _ in 0..lost_frames.min(MAX_CONCEALED_FRAMES) {
+                    decoded_data.extend(self.decoder.conceal()?);
+                }
+            }
+        }
+        self.last_sequence_number = Some(sequence_number);
+
+        decoded_data.extend(self.decoder.decode(&audio_frame_decoded.samples)?);
+        Ok(convert_i16_sample_to_f32(&decoded_data))
+    }
+
+    // Starts over after the stream was interrupted (e.g. a resumed session), the gap is not
+    // concealed and the next frame is taken as is
+    pub fn reset(&mut self) -> anyhow::Result<()> {
+        self.decoder.reset()?;
+        self.last_sequence_number = None;
+        Ok(())
     }
 }
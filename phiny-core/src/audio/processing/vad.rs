@@ -0,0 +1,44 @@
+// Frames kept flagged as speech after the level dropped, so word endings and short pauses
+// are not cut
+const HANGOVER_FRAMES: u32 = 15;
+
+/// Energy based voice activity detection on captured frames
+///
+/// A frame holds speech when its RMS level is above `threshold`, or shortly after one did.
+/// Crude next to a real VAD, but enough to stop sending the audio of muted or silent
+/// participants.
+pub struct VoiceActivityDetector {
+    threshold: f32,
+    hangover: u32,
+}
+
+impl VoiceActivityDetector {
+    /// `threshold` is an RMS level between 0 and 1, e.g. 0.01 for a quiet room
+    pub fn new(threshold: f32) -> Self {
+        Self {
+            threshold,
+            hangover: 0,
+        }
+    }
+
+    pub fn is_speech(&mut self, samples: &[f32]) -> bool {
+        if samples.is_empty() {
+            return self.hangover > 0;
+        }
+        let energy =
+            samples.iter().map(|sample| sample * sample).sum::<f32>() / samples.len() as f32;
+        if energy.sqrt() >= self.threshold {
+            self.hangover = HANGOVER_FRAMES;
+            true
+        } else {
+            self.hangover = self.hangover.saturating_sub(1);
+            self.hangover > 0
+        }
+    }
+}
+
+impl Default for VoiceActivityDetector {
+    fn default() -> Self {
+        Self::new(0.01)
+    }
+}
@@ -0,0 +1,66 @@
+use std::{collections::HashSet, future::Future, pin::Pin, sync::Arc};
+
+use iroh::NodeId;
+
+type Predicate = dyn Fn(NodeId) -> Pin<Box<dyn Future<Output = bool> + Send>> + Send + Sync;
+
+/// Decides which peers may connect to a listening [`Peer`](crate::p2p::Peer)
+///
+/// The policy is checked as soon as QUIC authenticated the remote [`NodeId`], before the phiny
+/// handshake. Denied peers are closed with [`CloseCode::AccessDenied`](crate::p2p::CloseCode).
+#[derive(Clone, Default)]
+pub enum AccessPolicy {
+    /// Anyone holding our address may connect
+    #[default]
+    AllowAll,
+    /// Only these peers may connect
+    Allowlist(HashSet<NodeId>),
+    /// Everyone but these peers may connect
+    Blocklist(HashSet<NodeId>),
+    /// The predicate decides, e.g. by looking the peer up in a contact list
+    Custom(Arc<Predicate>),
+}
+
+impl AccessPolicy {
+    /// A policy deciding with an async predicate
+    pub fn custom<F, Fut>(predicate: F) -> Self
+    where
+        F: Fn(NodeId) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = bool> + Send + 'static,
+    {
+        AccessPolicy::Custom(Arc::new(move |node_id| Box::pin(predicate(node_id))))
+    }
+
+    /// Whether `node_id` may connect
+    pub async fn allows(&self, node_id: NodeId) -> bool {
+        match self {
+            AccessPolicy::AllowAll => true,
+            AccessPolicy::Allowlist(allowed) => allowed.contains(&node_id),
+            AccessPolicy::Blocklist(blocked) => !blocked.contains(&node_id),
+            AccessPolicy::Custom(predicate) => predicate(node_id).await,
+        }
+    }
+}
+
+impl std::fmt::Debug for AccessPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AccessPolicy::AllowAll => write!(f, "AllowAll"),
+            AccessPolicy::Allowlist(allowed) => f.debug_tuple("Allowlist").field(allowed).finish(),
+            AccessPolicy::Blocklist(blocked) => f.debug_tuple("Blocklist").field(blocked).finish(),
+            AccessPolicy::Custom(_) => write!(f, "Custom(..)"),
+        }
+    }
+}
+
+/// The listener's [`AccessPolicy`] denied a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccessDenied(pub NodeId);
+
+impl std::fmt::Display for AccessDenied {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "access denied to {}", self.0)
+    }
+}
+
+impl std::error::Error for AccessDenied {}
@@ -0,0 +1,112 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+// Beyond this many tracked remotes, the ones whose window expired are forgotten
+const MAX_TRACKED_REMOTES: usize = 1024;
+
+/// How many handshake attempts a single remote address may make in a time window
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub attempts: u32,
+    pub per: Duration,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit {
+            attempts: 10,
+            per: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Fixed window counter of handshake attempts per remote address
+pub(crate) struct RateLimiter {
+    limit: RateLimit,
+    windows: HashMap<IpAddr, (Instant, u32)>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limit: RateLimit) -> Self {
+        RateLimiter {
+            limit,
+            windows: HashMap::new(),
+        }
+    }
+
+    /// Count an attempt from `remote`, false if it went over the limit
+    pub(crate) fn allow(&mut self, remote: IpAddr) -> bool {
+        let now = Instant::now();
+        let per = self.limit.per;
+        if self.windows.len() >= MAX_TRACKED_REMOTES {
+            self.windows
+                .retain(|_, (started, _)| now.duration_since(*started) < per);
+        }
+
+        let (started, attempts) = self.windows.entry(remote).or_insert((now, 0));
+        if now.duration_since(*started) >= per {
+            *started = now;
+            *attempts = 0;
+        }
+        *attempts += 1;
+        *attempts <= self.limit.attempts
+    }
+}
+
+/// Snapshot of what a [`ConnectionListener`](crate::p2p::ConnectionListener) let in and kept out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ListenerStats {
+    /// Connections currently holding a slot, including the ones still handshaking
+    pub active_connections: usize,
+    /// Upper bound of `active_connections`, see [`PeerConfig::max_connections`](crate::p2p::PeerConfig::max_connections)
+    pub max_connections: usize,
+    /// Connections which completed the handshake
+    pub accepted: u64,
+    /// Connections closed as busy because every slot was taken
+    pub rejected_busy: u64,
+    /// Connection attempts refused because their remote went over the rate limit
+    pub rejected_rate_limited: u64,
+    /// Connections closed because the access policy denied the peer
+    pub rejected_access_denied: u64,
+    /// Connections which failed the handshake
+    pub failed_handshakes: u64,
+}
+
+/// Counters shared by the accept loop of a listener and its handle
+#[derive(Default)]
+pub(crate) struct AdmissionCounters {
+    pub(crate) accepted: AtomicU64,
+    pub(crate) rejected_busy: AtomicU64,
+    pub(crate) rejected_rate_limited: AtomicU64,
+    pub(crate) rejected_access_denied: AtomicU64,
+    pub(crate) failed_handshakes: AtomicU64,
+}
+
+impl AdmissionCounters {
+    pub(crate) fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(
+        &self,
+        active_connections: usize,
+        max_connections: usize,
+    ) -> ListenerStats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        ListenerStats {
+            active_connections,
+            max_connections,
+            accepted: load(&self.accepted),
+            rejected_busy: load(&self.rejected_busy),
+            rejected_rate_limited: load(&self.rejected_rate_limited),
+            rejected_access_denied: load(&self.rejected_access_denied),
+            failed_handshakes: load(&self.failed_handshakes),
+        }
+    }
+}
@@ -0,0 +1,299 @@
+use std::{
+    collections::HashMap,
+    marker::PhantomData,
+    sync::{
+        Arc, Mutex as StdMutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+use anyhow::Result;
+use tokio::sync::{Mutex, mpsc};
+
+use crate::p2p::connection::Message;
+
+/// Tag carried by every frame on a [`Connection`](crate::p2p::Connection), telling the receiving
+/// side which [`Message`] type the payload belongs to
+///
+/// Ids below [`Channel::APPLICATION_START`] are reserved for phiny itself, applications pick
+/// their channels with [`Channel::application`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Channel(u16);
+
+impl Channel {
+    /// First channel id available to applications
+    pub const APPLICATION_START: u16 = 256;
+
+    pub(crate) const CONTROL: Channel = Channel(0);
+    pub(crate) const SIGNALING: Channel = Channel(1);
+    pub(crate) const ROOM: Channel = Channel(2);
+    pub(crate) const RELAYED: Channel = Channel(3);
+    pub(crate) const CHAT: Channel = Channel(4);
+
+    /// An application defined channel, `id` is offset past the reserved range
+    pub const fn application(id: u16) -> Self {
+        Channel(Self::APPLICATION_START + id)
+    }
+
+    pub const fn id(&self) -> u16 {
+        self.0
+    }
+
+    pub(crate) const fn from_id(id: u16) -> Self {
+        Channel(id)
+    }
+
+    // One of the channels phiny itself uses, their messages are kept until someone reads them
+    const fn is_builtin(&self) -> bool {
+        self.0 <= Self::CHAT.0
+    }
+}
+
+/// Receives the messages of a single type from a connection
+///
+/// Obtained from [`Connection::receiver`](crate::p2p::Connection::receiver) or
+/// [`Connection::unreliable_receiver`](crate::p2p::Connection::unreliable_receiver), it can be
+/// moved to its own task so every message type is consumed independently.
+pub struct MessageReceiver<M> {
+    inbox: Arc<Inbox>,
+    _message: PhantomData<fn() -> M>,
+}
+
+impl<M: Message> MessageReceiver<M> {
+    pub(crate) fn new(inbox: Arc<Inbox>) -> Self {
+        Self {
+            inbox,
+            _message: PhantomData,
+        }
+    }
+
+    /// Receive the next message, `None` once the connection is closed
+    pub async fn recv(&self) -> Result<Option<M>> {
+        match self.inbox.recv().await {
+            Some(data) => Ok(Some(M::deserialize(&data)?)),
+            None => Ok(None), // Connection closed
+        }
+    }
+}
+
+/// Messages of one channel waiting for their receiver
+pub(crate) struct Inbox {
+    receiver: Mutex<mpsc::UnboundedReceiver<Vec<u8>>>,
+    messages: AtomicUsize,
+    bytes: AtomicUsize,
+}
+
+impl Inbox {
+    async fn recv(&self) -> Option<Vec<u8>> {
+        let data = self.receiver.lock().await.recv().await?;
+        self.messages.fetch_sub(1, Ordering::Relaxed);
+        self.bytes.fetch_sub(data.len(), Ordering::Relaxed);
+        Some(data)
+    }
+}
+
+struct Route {
+    sender: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    inbox: Arc<Inbox>,
+    // A receiver was asked for, or the channel is one of phiny's own
+    claimed: bool,
+}
+
+/// How much a [`Router`] keeps for a channel nobody reads
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Backlog {
+    /// Drop new messages once this many are waiting
+    Messages(usize),
+    /// Never drop, report an overflow once this many bytes are waiting
+    Bytes(usize),
+}
+
+/// What became of a message handed to [`Router::deliver`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Delivery {
+    Queued,
+    /// Nobody receives the channel, or its queue is full
+    Dropped,
+    /// The channel is over its [`Backlog::Bytes`] limit, or too many channels nobody receives
+    /// are waiting; the message was not queued
+    Overflow,
+    Closed,
+}
+
+// Channels the remote may fill before we asked for a receiver, so it cannot make us allocate
+// one queue per channel id
+const MAX_UNCLAIMED_CHANNELS: usize = 16;
+
+#[derive(Default)]
+struct Routes {
+    routes: HashMap<Channel, Route>,
+    closed: bool,
+}
+
+/// Per channel queues between a network task and the message receivers
+///
+/// With [`Backlog::Bytes`] nothing is ever dropped: messages arriving before a receiver was asked
+/// for are kept, for at most [`MAX_UNCLAIMED_CHANNELS`] application channels, and a channel going
+/// over its limit is reported so the connection can be closed. With [`Backlog::Messages`]
+/// application channels only get a queue once a receiver is asked for, and a full queue drops.
+/// Delivery never waits, so a channel nobody reads cannot hold up the others.
+pub(crate) struct Router {
+    routes: StdMutex<Routes>,
+    backlog: Backlog,
+}
+
+impl Router {
+    pub(crate) fn new(backlog: Backlog) -> Self {
+        Self {
+            routes: StdMutex::new(Routes::default()),
+            backlog,
+        }
+    }
+
+    /// Queue a message received on `channel` for its receiver
+    pub(crate) fn deliver(&self, channel: Channel, data: Vec<u8>) -> Delivery {
+        let mut routes = self.routes.lock().unwrap();
+        if routes.closed {
+            return Delivery::Closed;
+        }
+        if !routes.routes.contains_key(&channel) && !channel.is_builtin() {
+            let unclaimed = routes
+                .routes
+                .values()
+                .filter(|route| !route.claimed)
+                .count();
+            match self.backlog {
+                Backlog::Messages(_) => return Delivery::Dropped,
+                Backlog::Bytes(_) if unclaimed >= MAX_UNCLAIMED_CHANNELS => {
+                    return Delivery::Overflow;
+                }
+                Backlog::Bytes(_) => {}
+            }
+        }
+        let route = Self::entry(&mut routes, channel);
+        let Some(sender) = &route.sender else {
+            return Delivery::Closed;
+        };
+        let inbox = &route.inbox;
+        match self.backlog {
+            Backlog::Messages(limit) if inbox.messages.load(Ordering::Relaxed) >= limit => {
+                return Delivery::Dropped;
+            }
+            Backlog::Bytes(limit) if inbox.bytes.load(Ordering::Relaxed) + data.len() > limit => {
+                return Delivery::Overflow;
+            }
+            _ => {}
+        }
+        let len = data.len();
+        if sender.send(data).is_err() {
+            return Delivery::Closed;
+        }
+        inbox.messages.fetch_add(1, Ordering::Relaxed);
+        inbox.bytes.fetch_add(len, Ordering::Relaxed);
+        Delivery::Queued
+    }
+
+    pub(crate) fn receiver(&self, channel: Channel) -> Arc<Inbox> {
+        let mut routes = self.routes.lock().unwrap();
+        let route = Self::entry(&mut routes, channel);
+        route.claimed = true;
+        Arc::clone(&route.inbox)
+    }
+
+    /// Drop every sender so receivers see the end of the connection
+    pub(crate) fn close(&self) {
+        let mut routes = self.routes.lock().unwrap();
+        routes.closed = true;
+        for route in routes.routes.values_mut() {
+            route.sender = None;
+        }
+    }
+
+    fn entry(routes: &mut Routes, channel: Channel) -> &mut Route {
+        // Channels created after close are born closed
+        let closed = routes.closed;
+        routes.routes.entry(channel).or_insert_with(|| {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            Route {
+                sender: (!closed).then_some(sender),
+                inbox: Arc::new(Inbox {
+                    receiver: Mutex::new(receiver),
+                    messages: AtomicUsize::new(0),
+                    bytes: AtomicUsize::new(0),
+                }),
+                claimed: channel.is_builtin(),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const APP: Channel = Channel::application(0);
+
+    #[tokio::test]
+    async fn messages_wait_for_a_receiver_created_later() {
+        let router = Router::new(Backlog::Bytes(1024));
+        assert_eq!(router.deliver(APP, vec![1]), Delivery::Queued);
+        assert_eq!(router.deliver(APP, vec![2]), Delivery::Queued);
+
+        let inbox = router.receiver(APP);
+        assert_eq!(inbox.recv().await, Some(vec![1]));
+        assert_eq!(inbox.recv().await, Some(vec![2]));
+    }
+
+    #[tokio::test]
+    async fn byte_limit_overflows_instead_of_dropping() {
+        let router = Router::new(Backlog::Bytes(4));
+        let inbox = router.receiver(APP);
+        assert_eq!(router.deliver(APP, vec![0; 3]), Delivery::Queued);
+        assert_eq!(router.deliver(APP, vec![0; 2]), Delivery::Overflow);
+
+        // Reading frees the room again
+        inbox.recv().await.unwrap();
+        assert_eq!(router.deliver(APP, vec![0; 4]), Delivery::Queued);
+    }
+
+    #[test]
+    fn unclaimed_channels_are_limited() {
+        let router = Router::new(Backlog::Bytes(1024));
+        for id in 0..MAX_UNCLAIMED_CHANNELS as u16 {
+            assert_eq!(
+                router.deliver(Channel::application(id), vec![0]),
+                Delivery::Queued
+            );
+        }
+        let extra = Channel::application(MAX_UNCLAIMED_CHANNELS as u16);
+        assert_eq!(router.deliver(extra, vec![0]), Delivery::Overflow);
+
+        // Phiny's own channels and claimed ones are not counted
+        assert_eq!(router.deliver(Channel::CHAT, vec![0]), Delivery::Queued);
+        router.receiver(extra);
+        assert_eq!(router.deliver(extra, vec![0]), Delivery::Queued);
+    }
+
+    #[test]
+    fn message_limit_drops() {
+        let router = Router::new(Backlog::Messages(1));
+        assert_eq!(router.deliver(APP, vec![0]), Delivery::Dropped);
+
+        router.receiver(APP);
+        assert_eq!(router.deliver(APP, vec![0]), Delivery::Queued);
+        assert_eq!(router.deliver(APP, vec![0]), Delivery::Dropped);
+    }
+
+    #[tokio::test]
+    async fn close_ends_the_receivers() {
+        let router = Router::new(Backlog::Bytes(1024));
+        let inbox = router.receiver(APP);
+        assert_eq!(router.deliver(APP, vec![1]), Delivery::Queued);
+        router.close();
+
+        assert_eq!(router.deliver(APP, vec![2]), Delivery::Closed);
+        assert_eq!(inbox.recv().await, Some(vec![1]));
+        assert_eq!(inbox.recv().await, None);
+        assert_eq!(router.receiver(Channel::application(1)).recv().await, None);
+    }
+}
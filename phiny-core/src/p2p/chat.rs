@@ -0,0 +1,197 @@
+use std::{
+    collections::HashSet,
+    sync::{
+        Arc, Mutex as StdMutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use bincode::{Decode, Encode};
+use iroh::NodeId;
+use log::debug;
+use tokio::{
+    sync::{Mutex, mpsc},
+    task::JoinHandle,
+};
+
+use crate::p2p::{
+    channel::Channel,
+    connection::{Connection, Message},
+    session::Session,
+    transport::Transport,
+};
+
+#[derive(Debug, Clone, Encode, Decode)]
+enum ChatFrame {
+    Text {
+        id: u64,
+        /// Milliseconds since the unix epoch, on the sender's clock
+        sent_at: u64,
+        text: String,
+    },
+    /// The message with this id was received
+    Ack { id: u64 },
+}
+
+impl Message for ChatFrame {
+    const CHANNEL: Channel = Channel::CHAT;
+
+    fn serialize(&self) -> Result<Vec<u8>> {
+        Ok(bincode::encode_to_vec(self, bincode::config::standard())?)
+    }
+
+    fn deserialize(data: &[u8]) -> Result<Self> {
+        Ok(bincode::decode_from_slice(data, bincode::config::standard())?.0)
+    }
+}
+
+/// Identifies a chat message among the ones sent by the same side of a [`Chat`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ChatMessageId(u64);
+
+impl std::fmt::Display for ChatMessageId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A text message received from the remote peer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChatMessage {
+    pub id: ChatMessageId,
+    pub from: NodeId,
+    /// When the sender sent it, according to the sender's clock
+    pub sent_at: SystemTime,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChatEvent {
+    Received(ChatMessage),
+    /// The remote peer received a message we sent
+    Delivered(ChatMessageId),
+}
+
+/// Text chat with the remote peer, next to whatever else runs on the connection
+///
+/// Messages travel on a channel reserved by phiny, so a chat and a [`Call`](crate::p2p::Call)
+/// share the connection without getting in each other's way. Every received message is
+/// acknowledged, [`ChatEvent::Delivered`] reports the acknowledgements of ours.
+pub struct Chat {
+    transport: Transport,
+    next_id: AtomicU64,
+    pending: Arc<StdMutex<HashSet<ChatMessageId>>>,
+    events: Mutex<mpsc::UnboundedReceiver<ChatEvent>>,
+    receive_task: JoinHandle<()>,
+}
+
+impl Chat {
+    /// Chat over a connection
+    pub fn open(connection: Arc<Connection>) -> Self {
+        Self::spawn(Transport::Connection(connection))
+    }
+
+    /// Like [`Chat::open`], the chat survives the session resuming on a new connection
+    pub fn open_session(session: Arc<Session>) -> Self {
+        Self::spawn(Transport::Session(session))
+    }
+
+    fn spawn(transport: Transport) -> Self {
+        let (events_tx, events) = mpsc::unbounded_channel();
+        let pending = Arc::new(StdMutex::new(HashSet::new()));
+        let receive_task =
+            tokio::spawn(receive(transport.clone(), Arc::clone(&pending), events_tx));
+        Chat {
+            transport,
+            next_id: AtomicU64::new(1),
+            pending,
+            events: Mutex::new(events),
+            receive_task,
+        }
+    }
+
+    /// Send a text message, [`ChatEvent::Delivered`] reports when the peer received it
+    pub async fn send(&self, text: impl Into<String>) -> Result<ChatMessageId> {
+        let id = ChatMessageId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        let sent_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        self.pending.lock().unwrap().insert(id);
+        let sent = self
+            .transport
+            .send(ChatFrame::Text {
+                id: id.0,
+                sent_at,
+                text: text.into(),
+            })
+            .await;
+        if sent.is_err() {
+            self.pending.lock().unwrap().remove(&id);
+        }
+        sent.map(|()| id)
+    }
+
+    /// Messages we sent which were not acknowledged yet
+    pub fn pending(&self) -> Vec<ChatMessageId> {
+        let mut pending: Vec<_> = self.pending.lock().unwrap().iter().copied().collect();
+        pending.sort();
+        pending
+    }
+
+    /// The next received message or acknowledgement, `None` once the connection (or session)
+    /// is gone
+    ///
+    /// Events wait until they are read, a message is only acknowledged once it was queued here.
+    pub async fn recv(&self) -> Option<ChatEvent> {
+        self.events.lock().await.recv().await
+    }
+}
+
+impl Drop for Chat {
+    fn drop(&mut self) {
+        self.receive_task.abort();
+    }
+}
+
+// Acknowledge the messages of the remote side and collect the acknowledgements of ours
+async fn receive(
+    transport: Transport,
+    pending: Arc<StdMutex<HashSet<ChatMessageId>>>,
+    events: mpsc::UnboundedSender<ChatEvent>,
+) {
+    loop {
+        match transport.receive::<ChatFrame>().await {
+            Ok(Some(ChatFrame::Text { id, sent_at, text })) => {
+                let message = ChatMessage {
+                    id: ChatMessageId(id),
+                    from: transport.connection().remote_node_id(),
+                    sent_at: UNIX_EPOCH + Duration::from_millis(sent_at),
+                    text,
+                };
+                // Only what reached the application is acknowledged, the queue never fills up
+                // so acknowledgements keep flowing
+                if events.send(ChatEvent::Received(message)).is_err() {
+                    return;
+                }
+                if let Err(e) = transport.send(ChatFrame::Ack { id }).await {
+                    debug!("Failed to acknowledge chat message {}: {}", id, e);
+                }
+            }
+            Ok(Some(ChatFrame::Ack { id })) => {
+                let id = ChatMessageId(id);
+                if !pending.lock().unwrap().remove(&id) {
+                    debug!("Ignoring acknowledgement of unknown chat message {}", id);
+                    continue;
+                }
+                if events.send(ChatEvent::Delivered(id)).is_err() {
+                    return;
+                }
+            }
+            Ok(None) => return,
+            Err(e) => debug!("Ignoring malformed chat message: {}", e),
+        }
+    }
+}
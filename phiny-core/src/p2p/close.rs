@@ -0,0 +1,73 @@
+use iroh::endpoint::VarInt;
+
+/// Phiny specific application error codes used when closing the underlying QUIC connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseCode {
+    /// The connection was closed on purpose without any error
+    Normal,
+    /// The handshake failed or the peers are not compatible
+    HandshakeFailed,
+    /// The remote violated the framing protocol, e.g. sent an oversized frame
+    ProtocolError,
+    /// The listener is shutting down
+    GoingAway,
+    /// The peer stopped answering heartbeats
+    Timeout,
+    /// The listener is at its connection limit
+    Busy,
+    /// The listener does not accept connections from this peer
+    AccessDenied,
+    /// A code which is not known to this version of phiny
+    Unknown(u64),
+}
+
+impl CloseCode {
+    pub fn code(&self) -> u64 {
+        match self {
+            CloseCode::Normal => 0,
+            CloseCode::HandshakeFailed => 1,
+            CloseCode::ProtocolError => 2,
+            CloseCode::GoingAway => 3,
+            CloseCode::Timeout => 4,
+            CloseCode::Busy => 5,
+            CloseCode::AccessDenied => 6,
+            CloseCode::Unknown(code) => *code,
+        }
+    }
+
+    pub fn from_code(code: u64) -> Self {
+        match code {
+            0 => CloseCode::Normal,
+            1 => CloseCode::HandshakeFailed,
+            2 => CloseCode::ProtocolError,
+            3 => CloseCode::GoingAway,
+            4 => CloseCode::Timeout,
+            5 => CloseCode::Busy,
+            6 => CloseCode::AccessDenied,
+            code => CloseCode::Unknown(code),
+        }
+    }
+
+    pub(crate) fn to_varint(self) -> VarInt {
+        VarInt::from_u64(self.code()).unwrap_or(VarInt::MAX)
+    }
+
+    pub(crate) fn from_varint(code: VarInt) -> Self {
+        CloseCode::from_code(code.into_inner())
+    }
+}
+
+impl std::fmt::Display for CloseCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CloseCode::Normal => write!(f, "normal"),
+            CloseCode::HandshakeFailed => write!(f, "handshake failed"),
+            CloseCode::ProtocolError => write!(f, "protocol error"),
+            CloseCode::GoingAway => write!(f, "going away"),
+            CloseCode::Timeout => write!(f, "timeout"),
+            CloseCode::Busy => write!(f, "busy"),
+            CloseCode::AccessDenied => write!(f, "access denied"),
+            CloseCode::Unknown(code) => write!(f, "unknown ({})", code),
+        }
+    }
+}
@@ -1,12 +1,45 @@
-use anyhow::Result;
-use iroh::endpoint::{RecvStream, SendStream};
+use std::{
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
+
+use anyhow::{Context as _, Result, anyhow};
+use iroh::{
+    Endpoint, NodeAddr, NodeId, Watcher as _,
+    endpoint::{self, ConnectionType, RecvStream, SendDatagramError, SendStream},
+};
+use log::debug;
+
+use crate::p2p::{
+    ALPN, PeerConfig,
+    channel::{Backlog, Channel, Delivery, MessageReceiver, Router},
+    close::CloseCode,
+    control::ControlMessage,
+    handshake::{Hello, NegotiatedConfig},
+    keepalive::{Heartbeat, RttEstimate},
+    queue::{Outgoing, Pushed, SendPolicy, SendQueue},
+    sas::ShortAuthString,
+    session::SessionId,
+    stats::{ConnectionStats, Counters},
+    status::{ConnectionStatus, StatusExt},
+};
 use tokio::{
     select,
-    sync::{mpsc, oneshot},
+    sync::{Notify, oneshot, watch},
 };
 
+// Large frames are read in chunks of this size, so memory is only committed for data which
+// actually arrived rather than for whatever length the remote announced
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
 /// Represents a message that can be sent over the p2p connection
+///
+/// Every message type travels on its own [`Channel`], so different types can share one
+/// connection and are received independently of each other.
 pub trait Message: Send + Sync + 'static {
+    /// The channel this message type is sent and received on
+    const CHANNEL: Channel;
+
     fn serialize(&self) -> Result<Vec<u8>>;
     fn deserialize(data: &[u8]) -> Result<Self>
     where
@@ -14,106 +47,717 @@ pub trait Message: Send + Sync + 'static {
 }
 
 /// Represents a p2p connection between two peers
+///
+/// All methods take `&self`, so a connection can be shared between tasks behind an `Arc`.
+///
+/// Messages sent with [`Connection::send`] travel over a reliable, ordered QUIC stream and are
+/// meant for control traffic. Real-time media should use [`Connection::send_unreliable`], which
+/// carries each message in its own QUIC datagram so a lost packet never delays later ones.
+/// When media has to go over the stream anyway, give its channel a [`SendPolicy`] so a stalled
+/// network drops frames instead of blocking the sender.
 pub struct Connection {
-    sender: mpsc::Sender<Vec<u8>>,
-    receiver: mpsc::Receiver<Vec<u8>>,
-    _close_signal: oneshot::Sender<()>,
+    connection: endpoint::Connection,
+    remote_node_id: NodeId,
+    alpn: Vec<u8>,
+    short_auth_string: ShortAuthString,
+    queue: Arc<SendQueue>,
+    router: Arc<Router>,
+    datagram_router: Arc<Router>,
+    remote_hello: Hello,
+    negotiated: NegotiatedConfig,
+    max_frame_size: usize,
+    status: Arc<watch::Sender<ConnectionStatus>>,
+    close_ack: Arc<Notify>,
+    close_timeout: Duration,
+    heartbeat: Arc<StdMutex<Heartbeat>>,
+    keepalive_interval: Duration,
+    counters: Arc<Counters>,
+    _close_signal: watch::Sender<()>,
 }
 
 impl Connection {
     pub(crate) fn new(
+        endpoint: &Endpoint,
+        connection: endpoint::Connection,
         send_stream: SendStream,
         recv_stream: RecvStream,
-        buffer_size: usize,
-    ) -> Self {
-        let (sender, mut sender_rx) = mpsc::channel::<Vec<u8>>(buffer_size);
-        let (receiver_tx, receiver) = mpsc::channel(buffer_size);
-        let (close_tx, close_rx) = oneshot::channel();
+        config: &PeerConfig,
+        remote_hello: Hello,
+        negotiated: NegotiatedConfig,
+    ) -> Result<Self> {
+        let remote_node_id = connection
+            .remote_node_id()
+            .context("Peer did not present its node id")?;
+        let alpn = connection.alpn().unwrap_or_else(|| ALPN.to_vec());
+        let short_auth_string =
+            ShortAuthString::derive(&connection, endpoint.node_id(), remote_node_id)?;
+        let buffer_size = config.buffer_size;
+        let max_frame_size = config.max_frame_size;
+        let queue = Arc::new(SendQueue::new(buffer_size, config.send_policies.clone()));
+        let max_receive_buffer = config.max_receive_buffer.max(max_frame_size);
+        let router = Arc::new(Router::new(Backlog::Bytes(max_receive_buffer)));
+        let datagram_router = Arc::new(Router::new(Backlog::Messages(buffer_size)));
+        let (close_tx, close_rx) = watch::channel(());
+        let status = Arc::new(watch::Sender::new(ConnectionStatus::Connected));
+        let counters = Arc::new(Counters::default());
 
         //Now lets spawn the task for sending and receiving from the network
         //sending loop
+        let mut send_close_rx = close_rx.clone();
+        let send_connection = connection.clone();
+        let send_status = Arc::clone(&status);
+        let send_counters = Arc::clone(&counters);
+        let send_queue = Arc::clone(&queue);
         tokio::spawn(async move {
             let mut send_stream = send_stream;
-            let mut close_rx = close_rx;
             loop {
                 select! {
-                                 Some(data) = sender_rx.recv()=> {
+                    outgoing = send_queue.pop() => {
+                        let (channel, data) = match outgoing {
+                            Outgoing::Frame(channel, data) => (channel, data),
+                            Outgoing::Finish(done) => {
+                                // Wait for the peer to acknowledge everything we wrote
+                                if send_stream.finish().is_ok() {
+                                    let _ = send_stream.stopped().await;
+                                }
+                                let _ = done.send(());
+                                break;
+                            }
+                        };
 
-                                    let len = data.len() as u32;
+                        // Frame layout: channel (u16 BE) | length (u32 BE) | payload
+                        let mut header = [0u8; 6];
+                        header[..2].copy_from_slice(&channel.id().to_be_bytes());
+                        header[2..].copy_from_slice(&(data.len() as u32).to_be_bytes());
 
-                                    if let Err(e) = send_stream.write_all(&len.to_be_bytes()).await {
-                                        eprintln!("Error while sending message length : {}", e);
-                                    }
+                        let written = match send_stream.write_all(&header).await {
+                            Ok(_) => send_stream.write_all(&data).await,
+                            Err(e) => Err(e),
+                        };
+                        if let Err(e) = written {
+                            debug!("Error while sending the message : {}", e);
+                            send_status.terminate(stream_failure(&send_connection, e));
+                            break;
+                        }
+                        Counters::record(
+                            &send_counters.messages_sent,
+                            &send_counters.bytes_sent,
+                            data.len(),
+                        );
 
-                                    if let Err(e) = send_stream.write_all(&data).await {
-                                        eprintln!("Error while sending the message : {}", e);
-                                    }
-                                },
-                                _=&mut close_rx=>{
-                break;
-                                }
-                                }
+                        // The network caught up with everything we queued
+                        if send_queue.is_empty() {
+                            send_status.set_live(ConnectionStatus::Connected);
+                        }
+                    },
+                    // Sender dropped, hence connection closed
+                    _ = send_close_rx.changed() => break,
+                }
             }
+            send_queue.close();
         });
         //receiving loop
+        let receive_router = Arc::clone(&router);
+        let receive_connection = connection.clone();
+        let receive_status = Arc::clone(&status);
+        let receive_counters = Arc::clone(&counters);
+        let mut receive_close_rx = close_rx.clone();
         tokio::spawn(async move {
             let mut recv_stream = recv_stream;
 
             loop {
-                let mut len_buffer = [0u8; 4];
-                match recv_stream.read_exact(&mut len_buffer).await {
+                let mut header = [0u8; 6];
+                let read = select! {
+                    read = recv_stream.read_exact(&mut header) => read,
+                    _ = receive_close_rx.changed() => break,
+                };
+                match read {
                     Err(e) => {
-                        eprintln!("Error while reading the message length : {}", e);
+                        debug!("Error while reading the message header : {}", e);
+                        receive_status.terminate(stream_failure(&receive_connection, e));
                         break;
                     }
                     Ok(_) => {
-                        let len = u32::from_be_bytes(len_buffer) as usize;
-                        let mut buffer = vec![0u8; len];
+                        let channel = Channel::from_id(u16::from_be_bytes([header[0], header[1]]));
+                        let len = u32::from_be_bytes([header[2], header[3], header[4], header[5]])
+                            as usize;
+
+                        if len > max_frame_size {
+                            let reason = format!(
+                                "frame of {} bytes exceeds the limit of {} bytes",
+                                len, max_frame_size
+                            );
+                            close_protocol_error(&receive_connection, &receive_status, reason);
+                            break;
+                        }
 
-                        match recv_stream.read_exact(buffer.as_mut_slice()).await {
+                        match read_frame(&mut recv_stream, len).await {
                             Err(e) => {
-                                eprintln!("Error while reading the message data : {}", e);
+                                debug!("Error while reading the message data : {}", e);
+                                receive_status.terminate(stream_failure(&receive_connection, e));
                                 break;
                             }
-                            Ok(_) => {
-                                if receiver_tx.send(buffer).await.is_err() {
-                                    // It means receiver channel is dropped and hence connection closed
-                                    break;
+                            Ok(buffer) => {
+                                Counters::record(
+                                    &receive_counters.messages_received,
+                                    &receive_counters.bytes_received,
+                                    buffer.len(),
+                                );
+                                match receive_router.deliver(channel, buffer) {
+                                    Delivery::Queued => {}
+                                    // The stream is reliable, rather than losing a message we
+                                    // give up on a peer sending more than we can buffer
+                                    Delivery::Dropped | Delivery::Overflow => {
+                                        let reason = format!(
+                                            "more than {} bytes waiting to be received on {:?}",
+                                            max_receive_buffer, channel
+                                        );
+                                        close_protocol_error(
+                                            &receive_connection,
+                                            &receive_status,
+                                            reason,
+                                        );
+                                        break;
+                                    }
+                                    Delivery::Closed => break,
                                 }
                             }
                         }
                     }
                 }
             }
+            // Let every receiver know that the connection is closed
+            receive_router.close();
+        });
+
+        //datagram receiving loop
+        let datagram_connection = connection.clone();
+        let receive_datagram_router = Arc::clone(&datagram_router);
+        let datagram_counters = Arc::clone(&counters);
+        let mut datagram_close_rx = close_rx.clone();
+        tokio::spawn(async move {
+            loop {
+                select! {
+                    result = datagram_connection.read_datagram() => match result {
+                        Err(e) => {
+                            debug!("Datagram receiver stopped : {}", e);
+                            break;
+                        }
+                        Ok(data) if data.len() < 2 => {
+                            debug!("Dropping datagram without channel");
+                        }
+                        Ok(data) => {
+                            Counters::record(
+                                &datagram_counters.datagrams_received,
+                                &datagram_counters.datagram_bytes_received,
+                                data.len(),
+                            );
+                            let channel = Channel::from_id(u16::from_be_bytes([data[0], data[1]]));
+                            // Media is only useful while it is fresh, so when the application
+                            // falls behind we drop the datagram instead of queueing it up
+                            match receive_datagram_router.deliver(channel, data[2..].to_vec()) {
+                                Delivery::Queued => {}
+                                Delivery::Dropped | Delivery::Overflow => {
+                                    debug!("Nobody keeps up with {:?}, dropping datagram", channel);
+                                    datagram_counters
+                                        .dropped_messages
+                                        .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                                }
+                                Delivery::Closed => break,
+                            }
+                        }
+                    },
+                    // Sender dropped, hence connection closed
+                    _ = datagram_close_rx.changed() => break,
+                }
+            }
+            receive_datagram_router.close();
         });
 
-        Self {
-            sender,
-            receiver,
+        //control loop, answers the connection management messages of the peer
+        let control_receiver =
+            MessageReceiver::<ControlMessage>::new(router.receiver(Channel::CONTROL));
+        let control_queue = Arc::clone(&queue);
+        let control_status = Arc::clone(&status);
+        let close_ack = Arc::new(Notify::new());
+        let control_close_ack = Arc::clone(&close_ack);
+        let heartbeat = Arc::new(StdMutex::new(Heartbeat::new()));
+        let control_heartbeat = Arc::clone(&heartbeat);
+        let mut control_close_rx = close_rx.clone();
+        tokio::spawn(async move {
+            loop {
+                let message = select! {
+                    message = control_receiver.recv() => message,
+                    _ = control_close_rx.changed() => break,
+                };
+                match message {
+                    Ok(Some(ControlMessage::Close { code, reason })) => {
+                        // The peer closes the QUIC connection itself once we acknowledged
+                        control_status.terminate(ConnectionStatus::Closed {
+                            code: CloseCode::from_code(code),
+                            reason,
+                            by_remote: true,
+                        });
+                        if let Ok(data) = ControlMessage::CloseAck.serialize() {
+                            control_queue.push(Channel::CONTROL, data).await;
+                        }
+                    }
+                    Ok(Some(ControlMessage::CloseAck)) => control_close_ack.notify_one(),
+                    Ok(Some(ControlMessage::Ping { nonce })) => {
+                        if let Ok(data) = (ControlMessage::Pong { nonce }).serialize() {
+                            let _ = control_queue.try_push(Channel::CONTROL, data);
+                        }
+                    }
+                    Ok(Some(ControlMessage::Pong { nonce })) => {
+                        control_heartbeat.lock().unwrap().pong(nonce);
+                    }
+                    Ok(None) => break,
+                    Err(e) => debug!("Ignoring malformed control message : {}", e),
+                }
+            }
+        });
+
+        //keepalive loop, sends heartbeats and gives up on a peer which stopped answering them
+        let keepalive_interval = config.keepalive_interval;
+        let keepalive_timeout = config.keepalive_timeout;
+        let keepalive_connection = connection.clone();
+        let keepalive_queue = Arc::clone(&queue);
+        let keepalive_status = Arc::clone(&status);
+        let keepalive_heartbeat = Arc::clone(&heartbeat);
+        let mut keepalive_close_rx = close_rx.clone();
+        // A zero interval disables heartbeats, and with them the dead peer detection
+        if !keepalive_interval.is_zero() {
+            tokio::spawn(async move {
+                let mut ticks = tokio::time::interval(keepalive_interval);
+                ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                loop {
+                    select! {
+                        _ = ticks.tick() => {},
+                        _ = keepalive_close_rx.changed() => break,
+                    }
+
+                    let (nonce, silence, missed) = {
+                        let mut heartbeat = keepalive_heartbeat.lock().unwrap();
+                        (
+                            heartbeat.ping(),
+                            heartbeat.silence(),
+                            heartbeat.missed(keepalive_interval),
+                        )
+                    };
+
+                    if silence >= keepalive_timeout {
+                        let reason = format!("no heartbeat answered for {:?}", silence);
+                        debug!("Closing connection, {}", reason);
+                        keepalive_status.terminate(ConnectionStatus::Error(reason.clone()));
+                        keepalive_connection
+                            .close(CloseCode::Timeout.to_varint(), reason.as_bytes());
+                        break;
+                    }
+                    if missed > 0 {
+                        keepalive_status.set_live(ConnectionStatus::Degraded {
+                            reason: format!("missed {} heartbeats", missed),
+                        });
+                    } else {
+                        // The peer answers again, degradations for other reasons are left alone
+                        keepalive_status.send_if_modified(|status| match status {
+                            ConnectionStatus::Degraded { reason }
+                                if reason.starts_with("missed") =>
+                            {
+                                *status = ConnectionStatus::Connected;
+                                true
+                            }
+                            _ => false,
+                        });
+                    }
+
+                    // A full queue means the peer is slow anyway, the missing pong will tell
+                    if let Ok(data) = (ControlMessage::Ping { nonce }).serialize() {
+                        let _ = keepalive_queue.try_push(Channel::CONTROL, data);
+                    }
+                }
+            });
+        }
+
+        //path watcher, records when the peer is reached over another path
+        if let Some(mut path_watcher) = endpoint.conn_type(remote_node_id) {
+            let path_counters = Arc::clone(&counters);
+            let mut path_close_rx = close_rx.clone();
+            tokio::spawn(async move {
+                path_counters.record_path(path_watcher.get());
+                loop {
+                    select! {
+                        updated = path_watcher.updated() => match updated {
+                            Ok(connection_type) => {
+                                debug!("Connection path changed to {}", connection_type);
+                                path_counters.record_path(connection_type);
+                            }
+                            Err(_) => break,
+                        },
+                        _ = path_close_rx.changed() => break,
+                    }
+                }
+            });
+        }
+
+        //lifecycle watcher, reports why the QUIC connection went away
+        let closed_connection = connection.clone();
+        let closed_status = Arc::clone(&status);
+        let mut lifecycle_close_rx = close_rx;
+        tokio::spawn(async move {
+            select! {
+                error = closed_connection.closed() => {
+                    closed_status.terminate(ConnectionStatus::from_connection_error(&error));
+                },
+                _ = lifecycle_close_rx.changed() => {},
+            }
+        });
+
+        Ok(Self {
+            connection,
+            remote_node_id,
+            alpn,
+            short_auth_string,
+            queue,
+            router,
+            datagram_router,
+            remote_hello,
+            negotiated,
+            max_frame_size,
+            status,
+            close_ack,
+            close_timeout: config.close_timeout,
+            heartbeat,
+            keepalive_interval: config.keepalive_interval,
+            counters,
             _close_signal: close_tx,
+        })
+    }
+
+    /// The current lifecycle status of the connection
+    pub fn status(&self) -> ConnectionStatus {
+        self.status.borrow().clone()
+    }
+
+    /// Watch the lifecycle status of the connection as it changes
+    pub fn watch_status(&self) -> watch::Receiver<ConnectionStatus> {
+        self.status.subscribe()
+    }
+
+    /// Wait until the connection is closed or failed and return the final status
+    pub async fn closed(&self) -> ConnectionStatus {
+        let mut status = self.status.subscribe();
+        match status.wait_for(ConnectionStatus::is_terminal).await {
+            Ok(status) => status.clone(),
+            Err(_) => self.status(),
         }
     }
 
+    /// Round trip time to the peer measured with heartbeats, `None` until the first pong
+    pub fn rtt(&self) -> Option<RttEstimate> {
+        self.heartbeat.lock().unwrap().rtt()
+    }
+
+    /// Heartbeats the peer left unanswered since its last pong
+    pub fn missed_heartbeats(&self) -> u32 {
+        self.heartbeat
+            .lock()
+            .unwrap()
+            .missed(self.keepalive_interval)
+    }
+
+    /// Snapshot of the traffic, the QUIC path figures and the network paths of the connection
+    pub fn stats(&self) -> ConnectionStats {
+        self.counters.snapshot(
+            &self.connection.stats(),
+            self.queue.len(),
+            self.queue.dropped(),
+            self.rtt(),
+        )
+    }
+
+    // Keep `guard` alive until the connection is closed, failed or dropped
+    pub(crate) fn hold_until_closed<T: Send + 'static>(&self, guard: T) {
+        let mut status = self.status.subscribe();
+        tokio::spawn(async move {
+            let _ = status.wait_for(ConnectionStatus::is_terminal).await;
+            drop(guard);
+        });
+    }
+
+    // Fails with the reason the connection ended, if it did
+    fn ensure_open(&self) -> Result<()> {
+        let status = self.status.borrow();
+        if status.is_terminal() {
+            return Err(anyhow!("{}", *status));
+        }
+        Ok(())
+    }
+
+    /// The configuration agreed on with the peer during the handshake
+    pub fn negotiated(&self) -> &NegotiatedConfig {
+        &self.negotiated
+    }
+
+    /// The display name the peer announced during the handshake
+    pub fn remote_display_name(&self) -> Option<&str> {
+        self.remote_hello.display_name.as_deref()
+    }
+
+    /// The session this connection belongs to, if it was opened by a [`Session`](crate::p2p::Session)
+    pub fn session_id(&self) -> Option<SessionId> {
+        self.remote_hello.session
+    }
+
+    /// The identity of the peer, authenticated by the QUIC handshake
+    pub fn remote_node_id(&self) -> NodeId {
+        self.remote_node_id
+    }
+
+    /// The ALPN protocol the connection was established with
+    pub fn alpn(&self) -> &[u8] {
+        &self.alpn
+    }
+
+    /// How the peer is currently reached, it changes as iroh finds better paths
+    pub fn remote_addr(&self) -> NodeAddr {
+        let addr = NodeAddr::new(self.remote_node_id);
+        match self.counters.current_path() {
+            ConnectionType::Direct(direct) => addr.with_direct_addresses([direct]),
+            ConnectionType::Relay(relay) => addr.with_relay_url(relay),
+            ConnectionType::Mixed(direct, relay) => {
+                addr.with_relay_url(relay).with_direct_addresses([direct])
+            }
+            ConnectionType::None => addr,
+        }
+    }
+
+    /// Code to compare with the peer, over the call or another channel, to make sure nobody
+    /// intercepted the connection
+    pub fn short_auth_string(&self) -> ShortAuthString {
+        self.short_auth_string
+    }
+
+    /// Everything the peer announced about itself during the handshake
+    pub fn remote_hello(&self) -> &Hello {
+        &self.remote_hello
+    }
+
+    // Open a QUIC stream of its own next to the message stream, e.g. for bulk data
+    pub(crate) async fn open_stream(&self) -> Result<(SendStream, RecvStream)> {
+        self.ensure_open()?;
+        self.connection
+            .open_bi()
+            .await
+            .context("Failed to open a stream")
+    }
+
+    // Wait for the peer to open a stream with `open_stream`
+    pub(crate) async fn accept_stream(&self) -> Result<(SendStream, RecvStream)> {
+        self.ensure_open()?;
+        self.connection
+            .accept_bi()
+            .await
+            .context("Failed to accept a stream")
+    }
+
     /// Send a message to the peer
+    ///
+    /// Waits while the send queue is full, unless the [`SendPolicy`] of the message's channel
+    /// says to drop messages instead.
     pub async fn send<M: Message>(&self, message: M) -> Result<()> {
         let data = message.serialize()?;
-        self.sender.send(data).await?;
-        Ok(())
+        if data.len() > self.max_frame_size {
+            return Err(anyhow!(
+                "Message of {} bytes exceeds the maximum frame size of {} bytes",
+                data.len(),
+                self.max_frame_size
+            ));
+        }
+        self.ensure_open()?;
+
+        let pushed = match self.queue.try_push(M::CHANNEL, data) {
+            Ok(pushed) => pushed,
+            Err(data) => {
+                self.degrade_full_queue();
+                self.queue.push(M::CHANNEL, data).await
+            }
+        };
+        match pushed {
+            Pushed::Queued => Ok(()),
+            // Not an error, the policy of the channel asked for it
+            Pushed::Dropped => {
+                self.degrade_full_queue();
+                Ok(())
+            }
+            Pushed::Closed => Err(anyhow!("{}", self.status())),
+        }
+    }
+
+    fn degrade_full_queue(&self) {
+        self.status.set_live(ConnectionStatus::Degraded {
+            reason: "send queue is full".to_string(),
+        });
+    }
+
+    /// How the messages of `channel` are queued by [`Connection::send`]
+    pub fn send_policy(&self, channel: Channel) -> SendPolicy {
+        self.queue.policy(channel)
+    }
+
+    /// Change how the messages of `channel` are queued, e.g. to
+    /// [`SendPolicy::realtime`] for media sent over the stream
+    ///
+    /// Only this connection is affected, [`PeerConfig::send_policies`] applies to every
+    /// connection of a peer, resumed sessions included.
+    pub fn set_send_policy(&self, channel: Channel, policy: SendPolicy) {
+        self.queue.set_policy(channel, policy);
     }
 
     /// Receive a message from the peer
-    pub async fn receive<M: Message>(&mut self) -> Result<Option<M>> {
-        if let Some(data) = self.receiver.recv().await {
-            println!("Received at connection level");
-            let message = M::deserialize(&data)?;
-            Ok(Some(message))
-        } else {
-            Ok(None) // Connection closed
+    ///
+    /// Only messages of type `M` are returned, messages of other types stay queued on their
+    /// own channel, including the ones arriving before a receiver for it was asked for. The
+    /// connection is closed with [`CloseCode::ProtocolError`] when more than
+    /// [`PeerConfig::max_receive_buffer`] bytes are waiting to be read on a channel.
+    pub async fn receive<M: Message>(&self) -> Result<Option<M>> {
+        self.receiver::<M>().recv().await
+    }
+
+    /// A receiver for the messages of type `M`, which can be moved to another task
+    ///
+    /// Create it before the peer starts sending on the channel, see [`Connection::receive`].
+    pub fn receiver<M: Message>(&self) -> MessageReceiver<M> {
+        MessageReceiver::new(self.router.receiver(M::CHANNEL))
+    }
+
+    /// Send a message to the peer as an unreliable QUIC datagram
+    ///
+    /// The message is either delivered as a whole or lost; it is never retransmitted and may
+    /// arrive out of order. Fails if the peer does not support datagrams or the serialized
+    /// message does not fit in a single datagram.
+    pub fn send_unreliable<M: Message>(&self, message: M) -> Result<()> {
+        self.ensure_open()?;
+        let payload = message.serialize()?;
+        let mut data = Vec::with_capacity(payload.len() + 2);
+        data.extend_from_slice(&M::CHANNEL.id().to_be_bytes());
+        data.extend_from_slice(&payload);
+
+        let max_size = self
+            .connection
+            .max_datagram_size()
+            .ok_or_else(|| anyhow!("Peer does not support datagrams"))?;
+        if data.len() > max_size {
+            return Err(anyhow!(
+                "Message of {} bytes exceeds the maximum datagram size of {} bytes",
+                data.len(),
+                max_size
+            ));
+        }
+        let len = data.len();
+        self.connection
+            .send_datagram(data.into())
+            .map(|_| {
+                Counters::record(
+                    &self.counters.datagrams_sent,
+                    &self.counters.datagram_bytes_sent,
+                    len,
+                )
+            })
+            .map_err(|e| match e {
+                SendDatagramError::ConnectionLost(e) => {
+                    anyhow!("{}", ConnectionStatus::from_connection_error(&e))
+                }
+                e => e.into(),
+            })
+    }
+
+    /// Receive a message sent by the peer with [`Connection::send_unreliable`]
+    pub async fn receive_unreliable<M: Message>(&self) -> Result<Option<M>> {
+        self.unreliable_receiver::<M>().recv().await
+    }
+
+    /// A receiver for the messages of type `M` sent with [`Connection::send_unreliable`]
+    pub fn unreliable_receiver<M: Message>(&self) -> MessageReceiver<M> {
+        MessageReceiver::new(self.datagram_router.receiver(M::CHANNEL))
+    }
+
+    /// Gracefully close the connection with a code and a reason shown to the peer
+    ///
+    /// Everything sent before is delivered first: the peer is told we are closing, its
+    /// acknowledgement is awaited (bounded by [`PeerConfig::close_timeout`]), the send stream is
+    /// finished and finally the QUIC connection is closed with `code` and `reason`. Returns an
+    /// error if the peer did not acknowledge in time, the connection is closed either way.
+    pub async fn close(&self, code: CloseCode, reason: &str) -> Result<()> {
+        if self.status.borrow().is_terminal() {
+            return Ok(());
+        }
+
+        let graceful = async {
+            self.send(ControlMessage::Close {
+                code: code.code(),
+                reason: reason.to_string(),
+            })
+            .await?;
+            self.close_ack.notified().await;
+
+            let (done_tx, done_rx) = oneshot::channel();
+            if !self.queue.finish(done_tx) {
+                return Err(anyhow!("{}", self.status()));
+            }
+            let _ = done_rx.await;
+            Ok::<_, anyhow::Error>(())
+        };
+        let result = tokio::time::timeout(self.close_timeout, graceful).await;
+
+        self.connection.close(code.to_varint(), reason.as_bytes());
+        self.status.terminate(ConnectionStatus::Closed {
+            code,
+            reason: reason.to_string(),
+            by_remote: false,
+        });
+
+        match result {
+            Ok(result) => result.context("Failed to close the connection gracefully"),
+            Err(_) => Err(anyhow!(
+                "Timed out waiting for the peer to acknowledge the close"
+            )),
         }
     }
+}
+
+// Status of a connection whose stream failed, preferring the reason the QUIC connection closed
+fn stream_failure(
+    connection: &endpoint::Connection,
+    error: impl std::fmt::Display,
+) -> ConnectionStatus {
+    match connection.close_reason() {
+        Some(reason) => ConnectionStatus::from_connection_error(&reason),
+        None => ConnectionStatus::Error(error.to_string()),
+    }
+}
+
+// Close a connection whose peer broke the framing protocol
+fn close_protocol_error(
+    connection: &endpoint::Connection,
+    status: &watch::Sender<ConnectionStatus>,
+    reason: String,
+) {
+    debug!("Closing connection, {}", reason);
+    connection.close(CloseCode::ProtocolError.to_varint(), reason.as_bytes());
+    status.terminate(ConnectionStatus::Closed {
+        code: CloseCode::ProtocolError,
+        reason,
+        by_remote: false,
+    });
+}
 
-    /// Close the connection
-    pub fn close(self) {
-        // Dropping the close_signal will signal the background task to shut down
+// Read a frame payload of `len` bytes, growing the buffer as the data arrives
+async fn read_frame(recv_stream: &mut RecvStream, len: usize) -> Result<Vec<u8>> {
+    let mut buffer = Vec::with_capacity(len.min(READ_CHUNK_SIZE));
+    while buffer.len() < len {
+        let start = buffer.len();
+        buffer.resize(start + (len - start).min(READ_CHUNK_SIZE), 0);
+        recv_stream.read_exact(&mut buffer[start..]).await?;
     }
+    Ok(buffer)
 }
@@ -0,0 +1,103 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context as _, Result};
+use iroh::NodeId;
+use serde::{Deserialize, Serialize};
+
+/// A peer we called or were called by before
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Contact {
+    pub node_id: NodeId,
+    /// Name the peer last announced, or the one given when it was added
+    pub name: Option<String>,
+    /// Both sides compared the [`ShortAuthString`](crate::p2p::ShortAuthString) of a call
+    #[serde(default)]
+    pub verified: bool,
+}
+
+/// Address book of peers, kept as a JSON file
+///
+/// Combined with [`AccessPolicy::custom`](crate::p2p::AccessPolicy::custom) it can restrict
+/// calls to known or verified peers.
+#[derive(Debug, Clone)]
+pub struct Contacts {
+    path: PathBuf,
+    contacts: BTreeMap<NodeId, Contact>,
+}
+
+impl Contacts {
+    /// Load the contacts at `path`, empty if the file does not exist yet
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let contacts: Vec<Contact> = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .with_context(|| format!("Contacts file {} is not valid", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read contacts file {}", path.display()));
+            }
+        };
+        Ok(Contacts {
+            path,
+            contacts: contacts
+                .into_iter()
+                .map(|contact| (contact.node_id, contact))
+                .collect(),
+        })
+    }
+
+    /// Write the contacts back to the file they were loaded from
+    pub fn save(&self) -> Result<()> {
+        if let Some(parent) = self
+            .path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create the directory {}", parent.display()))?;
+        }
+        let contacts: Vec<&Contact> = self.contacts.values().collect();
+        fs::write(&self.path, serde_json::to_string_pretty(&contacts)?)
+            .with_context(|| format!("Failed to write contacts file {}", self.path.display()))
+    }
+
+    pub fn get(&self, node_id: &NodeId) -> Option<&Contact> {
+        self.contacts.get(node_id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Contact> {
+        self.contacts.values()
+    }
+
+    pub fn is_verified(&self, node_id: &NodeId) -> bool {
+        self.get(node_id).is_some_and(|contact| contact.verified)
+    }
+
+    /// Add the peer if it is unknown, updating its name when one is given
+    pub fn add(&mut self, node_id: NodeId, name: Option<String>) -> &mut Contact {
+        let contact = self.contacts.entry(node_id).or_insert_with(|| Contact {
+            node_id,
+            name: None,
+            verified: false,
+        });
+        if name.is_some() {
+            contact.name = name;
+        }
+        contact
+    }
+
+    /// Record that the peer was verified, adding it if it is unknown
+    pub fn mark_verified(&mut self, node_id: NodeId, name: Option<String>) {
+        self.add(node_id, name).verified = true;
+    }
+
+    /// Forget a peer, returns it if it was known
+    pub fn remove(&mut self, node_id: &NodeId) -> Option<Contact> {
+        self.contacts.remove(node_id)
+    }
+}
@@ -0,0 +1,35 @@
+use anyhow::Result;
+use bincode::{Decode, Encode};
+
+use crate::p2p::{channel::Channel, connection::Message};
+
+/// Messages phiny exchanges internally to manage a connection
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub(crate) enum ControlMessage {
+    /// The sender is about to close the connection, see [`CloseCode`](crate::p2p::CloseCode)
+    Close {
+        code: u64,
+        reason: String,
+    },
+    /// Every message sent before the close was received
+    CloseAck,
+    /// Heartbeat, answered with a [`ControlMessage::Pong`] carrying the same nonce
+    Ping {
+        nonce: u64,
+    },
+    Pong {
+        nonce: u64,
+    },
+}
+
+impl Message for ControlMessage {
+    const CHANNEL: Channel = Channel::CONTROL;
+
+    fn serialize(&self) -> Result<Vec<u8>> {
+        Ok(bincode::encode_to_vec(self, bincode::config::standard())?)
+    }
+
+    fn deserialize(data: &[u8]) -> Result<Self> {
+        Ok(bincode::decode_from_slice(data, bincode::config::standard())?.0)
+    }
+}
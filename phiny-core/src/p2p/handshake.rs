@@ -0,0 +1,396 @@
+use anyhow::{Context as _, Result};
+use bincode::{Decode, Encode};
+use iroh::endpoint::{RecvStream, SendStream};
+
+use crate::p2p::session::SessionId;
+
+/// Version of the phiny wire protocol spoken by this build
+pub const PROTOCOL_VERSION: u16 = 1;
+
+// Every handshake frame starts with this magic followed by the protocol version, so garbage
+// and peers speaking another protocol are rejected before we try to parse anything else
+const HANDSHAKE_MAGIC: [u8; 6] = *b"PHINY/";
+const MAX_HANDSHAKE_SIZE: usize = 16 * 1024;
+
+/// Audio codecs a peer is able to encode and decode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub enum Codec {
+    Opus,
+}
+
+/// Optional protocol features, negotiated as the intersection of both peers' flags
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Encode, Decode)]
+pub struct Features(u32);
+
+impl Features {
+    /// Audio frames are carried over unreliable QUIC datagrams
+    pub const DATAGRAMS: Features = Features(1 << 0);
+
+    pub const fn empty() -> Self {
+        Features(0)
+    }
+
+    pub const fn all() -> Self {
+        Features::DATAGRAMS
+    }
+
+    pub fn contains(&self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersection(&self, other: Features) -> Features {
+        Features(self.0 & other.0)
+    }
+}
+
+impl std::ops::BitOr for Features {
+    type Output = Features;
+
+    fn bitor(self, rhs: Features) -> Features {
+        Features(self.0 | rhs.0)
+    }
+}
+
+/// What a peer supports, ordered by preference where it matters
+#[derive(Debug, Clone, Encode, Decode)]
+pub struct Capabilities {
+    pub codecs: Vec<Codec>,
+    /// Audio frame durations in milliseconds
+    pub frame_durations_ms: Vec<u16>,
+    pub sample_rates: Vec<u32>,
+    pub features: Features,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Capabilities {
+            codecs: vec![Codec::Opus],
+            frame_durations_ms: vec![20],
+            sample_rates: vec![48000],
+            features: Features::all(),
+        }
+    }
+}
+
+/// The handshake message each peer sends to introduce itself
+#[derive(Debug, Clone, Encode, Decode)]
+pub struct Hello {
+    pub display_name: Option<String>,
+    pub capabilities: Capabilities,
+    /// The session this connection belongs to, the listener echoes the connector's id
+    pub session: Option<SessionId>,
+    /// The signed ticket the connector was invited with, encoded as a string
+    pub ticket: Option<String>,
+}
+
+/// Call configuration both peers agreed on during the handshake
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct NegotiatedConfig {
+    pub version: u16,
+    pub codec: Codec,
+    pub frame_duration_ms: u16,
+    pub sample_rate: u32,
+    pub features: Features,
+}
+
+#[derive(Debug, Encode, Decode)]
+enum HandshakeResponse {
+    Accept {
+        hello: Hello,
+        config: NegotiatedConfig,
+    },
+    Reject {
+        reason: String,
+    },
+}
+
+/// Reasons a handshake can fail
+#[derive(Debug)]
+pub enum HandshakeError {
+    /// The remote did not speak the phiny handshake at all
+    InvalidMagic,
+    /// The remote speaks a protocol version we do not support
+    UnsupportedVersion { local: u16, remote: u16 },
+    /// The handshake message could not be parsed
+    Malformed(String),
+    /// Both peers are valid but share no common configuration
+    Incompatible(String),
+    /// The remote peer refused our handshake
+    Rejected(String),
+    /// The remote peer did not present a valid invitation
+    Unauthorized(String),
+}
+
+impl std::fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HandshakeError::InvalidMagic => write!(f, "peer did not send a phiny handshake"),
+            HandshakeError::UnsupportedVersion { local, remote } => write!(
+                f,
+                "unsupported protocol version {} (we speak version {})",
+                remote, local
+            ),
+            HandshakeError::Malformed(e) => write!(f, "malformed handshake message: {}", e),
+            HandshakeError::Incompatible(e) => write!(f, "incompatible peer: {}", e),
+            HandshakeError::Rejected(reason) => write!(f, "handshake rejected by peer: {}", reason),
+            HandshakeError::Unauthorized(reason) => write!(f, "unauthorized: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+/// Run the connecting side of the handshake, returns the remote hello and the agreed config
+pub(crate) async fn initiate(
+    send: &mut SendStream,
+    recv: &mut RecvStream,
+    local: &Hello,
+) -> Result<(Hello, NegotiatedConfig)> {
+    write_frame(send, &encode(local)?).await?;
+
+    let response: HandshakeResponse = decode(&read_frame(recv).await?)?;
+    match response {
+        HandshakeResponse::Accept { hello, config } => {
+            // The listener must pick something we offered, otherwise it is not a valid answer
+            if config.version != PROTOCOL_VERSION
+                || !local.capabilities.codecs.contains(&config.codec)
+                || !local
+                    .capabilities
+                    .frame_durations_ms
+                    .contains(&config.frame_duration_ms)
+                || !local
+                    .capabilities
+                    .sample_rates
+                    .contains(&config.sample_rate)
+                || !local.capabilities.features.contains(config.features)
+            {
+                return Err(HandshakeError::Incompatible(format!(
+                    "peer chose a configuration we did not offer: {:?}",
+                    config
+                ))
+                .into());
+            }
+            Ok((hello, config))
+        }
+        HandshakeResponse::Reject { reason } => Err(HandshakeError::Rejected(reason).into()),
+    }
+}
+
+/// Run the listening side of the handshake, returns the remote hello and the agreed config
+///
+/// `validate` may refuse the remote hello once a configuration was negotiated, so whatever it
+/// takes (e.g. a use of an invite) is only taken by handshakes which would otherwise succeed.
+/// When the remote is rejected the reason is sent back before the error is returned.
+pub(crate) async fn respond(
+    send: &mut SendStream,
+    recv: &mut RecvStream,
+    local: &Hello,
+    validate: impl FnOnce(&Hello) -> Result<(), HandshakeError>,
+) -> Result<(Hello, NegotiatedConfig)> {
+    let result = read_frame(recv).await.and_then(|data| {
+        let remote: Hello = decode(&data)?;
+        let config = negotiate(&local.capabilities, &remote.capabilities)?;
+        validate(&remote)?;
+        Ok((remote, config))
+    });
+
+    match result {
+        Ok((remote, config)) => {
+            let response = HandshakeResponse::Accept {
+                hello: Hello {
+                    session: remote.session,
+                    ..local.clone()
+                },
+                config: config.clone(),
+            };
+            write_frame(send, &encode(&response)?).await?;
+            Ok((remote, config))
+        }
+        Err(e) => {
+            // Let the remote know why, garbage peers may not be listening so ignore failures
+            if e.downcast_ref::<HandshakeError>().is_some() {
+                let response = HandshakeResponse::Reject {
+                    reason: e.to_string(),
+                };
+                if let Ok(data) = encode(&response) {
+                    let _ = write_frame(send, &data).await;
+                }
+            }
+            Err(e)
+        }
+    }
+}
+
+// Picks the configuration both sides support, preferring the local order of preference
+fn negotiate(local: &Capabilities, remote: &Capabilities) -> Result<NegotiatedConfig> {
+    let codec = local
+        .codecs
+        .iter()
+        .find(|codec| remote.codecs.contains(codec))
+        .copied()
+        .ok_or_else(|| HandshakeError::Incompatible("no common codec".to_string()))?;
+    let frame_duration_ms = local
+        .frame_durations_ms
+        .iter()
+        .find(|duration| remote.frame_durations_ms.contains(duration))
+        .copied()
+        .ok_or_else(|| HandshakeError::Incompatible("no common frame duration".to_string()))?;
+    let sample_rate = local
+        .sample_rates
+        .iter()
+        .find(|rate| remote.sample_rates.contains(rate))
+        .copied()
+        .ok_or_else(|| HandshakeError::Incompatible("no common sample rate".to_string()))?;
+
+    Ok(NegotiatedConfig {
+        version: PROTOCOL_VERSION,
+        codec,
+        frame_duration_ms,
+        sample_rate,
+        features: local.features.intersection(remote.features),
+    })
+}
+
+fn encode<T: Encode>(value: &T) -> Result<Vec<u8>> {
+    Ok(bincode::encode_to_vec(value, bincode::config::standard())?)
+}
+
+fn decode<T: Decode<()>>(data: &[u8]) -> Result<T> {
+    let (value, _) = bincode::decode_from_slice(data, bincode::config::standard())
+        .map_err(|e| HandshakeError::Malformed(e.to_string()))?;
+    Ok(value)
+}
+
+// Frame layout: magic | version (u16 BE) | length (u32 BE) | bincode payload
+async fn write_frame(send: &mut SendStream, payload: &[u8]) -> Result<()> {
+    let mut frame = Vec::with_capacity(HANDSHAKE_MAGIC.len() + 6 + payload.len());
+    frame.extend_from_slice(&HANDSHAKE_MAGIC);
+    frame.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    send.write_all(&frame)
+        .await
+        .context("Failed to send handshake")?;
+    Ok(())
+}
+
+async fn read_frame(recv: &mut RecvStream) -> Result<Vec<u8>> {
+    let mut magic = [0u8; HANDSHAKE_MAGIC.len()];
+    recv.read_exact(&mut magic)
+        .await
+        .context("Failed to read handshake")?;
+    if magic != HANDSHAKE_MAGIC {
+        return Err(HandshakeError::InvalidMagic.into());
+    }
+
+    let mut version = [0u8; 2];
+    recv.read_exact(&mut version)
+        .await
+        .context("Failed to read handshake")?;
+    let version = u16::from_be_bytes(version);
+    if version != PROTOCOL_VERSION {
+        return Err(HandshakeError::UnsupportedVersion {
+            local: PROTOCOL_VERSION,
+            remote: version,
+        }
+        .into());
+    }
+
+    let mut len = [0u8; 4];
+    recv.read_exact(&mut len)
+        .await
+        .context("Failed to read handshake")?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_HANDSHAKE_SIZE {
+        return Err(HandshakeError::Malformed(format!(
+            "handshake of {} bytes exceeds the limit of {} bytes",
+            len, MAX_HANDSHAKE_SIZE
+        ))
+        .into());
+    }
+
+    let mut payload = vec![0u8; len];
+    recv.read_exact(&mut payload)
+        .await
+        .context("Failed to read handshake")?;
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capabilities(frame_durations_ms: Vec<u16>, features: Features) -> Capabilities {
+        Capabilities {
+            frame_durations_ms,
+            features,
+            ..Capabilities::default()
+        }
+    }
+
+    #[test]
+    fn both_sides_negotiate_the_same_config() {
+        let listener = capabilities(vec![10, 20, 40], Features::all());
+        let connector = capabilities(vec![20, 60], Features::empty());
+
+        let config = negotiate(&listener, &connector).unwrap();
+        assert_eq!(config, negotiate(&connector, &listener).unwrap());
+        assert_eq!(
+            config,
+            NegotiatedConfig {
+                version: PROTOCOL_VERSION,
+                codec: Codec::Opus,
+                frame_duration_ms: 20,
+                sample_rate: 48000,
+                features: Features::empty(),
+            }
+        );
+    }
+
+    #[test]
+    fn default_capabilities_keep_every_feature() {
+        let config = negotiate(&Capabilities::default(), &Capabilities::default()).unwrap();
+        assert!(config.features.contains(Features::DATAGRAMS));
+    }
+
+    #[test]
+    fn negotiation_follows_the_local_preference() {
+        let local = capabilities(vec![40, 20], Features::all());
+        let remote = capabilities(vec![20, 40], Features::all());
+        assert_eq!(negotiate(&local, &remote).unwrap().frame_duration_ms, 40);
+    }
+
+    #[test]
+    fn nothing_in_common_is_incompatible() {
+        let local = capabilities(vec![20], Features::all());
+        let remote = capabilities(vec![60], Features::all());
+        let error = negotiate(&local, &remote).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<HandshakeError>(),
+            Some(HandshakeError::Incompatible(_))
+        ));
+    }
+
+    #[test]
+    fn hello_survives_encoding() {
+        let hello = Hello {
+            display_name: Some("alice".to_string()),
+            capabilities: Capabilities::default(),
+            session: None,
+            ticket: Some("ticket".to_string()),
+        };
+        let decoded: Hello = decode(&encode(&hello).unwrap()).unwrap();
+        assert_eq!(decoded.display_name, hello.display_name);
+        assert_eq!(decoded.ticket, hello.ticket);
+        assert_eq!(decoded.capabilities.codecs, hello.capabilities.codecs);
+    }
+
+    #[test]
+    fn garbage_is_malformed() {
+        let error = decode::<Hello>(&[0xff; 3]).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<HandshakeError>(),
+            Some(HandshakeError::Malformed(_))
+        ));
+    }
+}
@@ -0,0 +1,73 @@
+use std::{fs, io::Write as _, path::Path};
+
+use anyhow::{Context as _, Result, anyhow};
+use data_encoding::{HEXLOWER, HEXLOWER_PERMISSIVE};
+use iroh::SecretKey;
+
+/// Generate a new random secret key, the identity of a peer
+pub fn generate_secret_key() -> SecretKey {
+    SecretKey::generate(&mut rand::rng())
+}
+
+/// Load a secret key saved with [`save_secret_key`]
+pub fn load_secret_key(path: impl AsRef<Path>) -> Result<SecretKey> {
+    let path = path.as_ref();
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read the key file {}", path.display()))?;
+    let bytes = HEXLOWER_PERMISSIVE
+        .decode(contents.trim().as_bytes())
+        .with_context(|| format!("Key file {} is not valid hex", path.display()))?;
+    let bytes: [u8; 32] = bytes.try_into().map_err(|bytes: Vec<u8>| {
+        anyhow!(
+            "Key file {} holds {} bytes instead of 32",
+            path.display(),
+            bytes.len()
+        )
+    })?;
+    Ok(SecretKey::from_bytes(&bytes))
+}
+
+/// Save a secret key as hex, the file is only readable by its owner
+pub fn save_secret_key(key: &SecretKey, path: impl AsRef<Path>) -> Result<()> {
+    let path = path.as_ref();
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create the directory {}", parent.display()))?;
+    }
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt as _;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("Failed to open the key file {}", path.display()))?;
+
+    // The mode only applies to new files, tighten an existing one as well
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt as _;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+
+    writeln!(file, "{}", HEXLOWER.encode(&key.to_bytes()))
+        .with_context(|| format!("Failed to write the key file {}", path.display()))?;
+    Ok(())
+}
+
+/// Load the secret key at `path`, generating and saving a new one if there is none yet
+pub fn load_or_generate_secret_key(path: impl AsRef<Path>) -> Result<SecretKey> {
+    let path = path.as_ref();
+    if path.exists() {
+        return load_secret_key(path);
+    }
+    let key = generate_secret_key();
+    save_secret_key(&key, path)?;
+    Ok(key)
+}
@@ -0,0 +1,246 @@
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bincode::{Decode, Encode};
+use data_encoding::{HEXLOWER, HEXLOWER_PERMISSIVE};
+use iroh::NodeId;
+
+use crate::p2p::ticket::unix_time;
+
+/// Secret embedded in an invite ticket, see [`Peer::invite`](crate::p2p::Peer::invite)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Encode, Decode)]
+pub struct InviteToken([u8; 16]);
+
+impl InviteToken {
+    /// A new random token
+    pub fn new() -> Self {
+        InviteToken(rand::random())
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+}
+
+impl Default for InviteToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Display for InviteToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", HEXLOWER.encode(&self.0))
+    }
+}
+
+impl FromStr for InviteToken {
+    type Err = InviteError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        HEXLOWER_PERMISSIVE
+            .decode(s.trim().as_bytes())
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .map(InviteToken)
+            .ok_or(InviteError::Unknown)
+    }
+}
+
+/// An outstanding invite issued by a listening peer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invite {
+    pub token: InviteToken,
+    /// How many different peers may join with this invite
+    pub max_uses: u32,
+    /// Peers which joined with this invite, they may reconnect with it as often as needed until
+    /// [`PeerConfig::invite_grace`](crate::p2p::PeerConfig::invite_grace) after its expiry
+    pub redeemed_by: Vec<NodeId>,
+    /// Unix time in seconds after which the invite is no longer accepted
+    pub expires_at: Option<u64>,
+}
+
+impl Invite {
+    /// Peers which may still join with this invite
+    pub fn remaining_uses(&self) -> u32 {
+        self.max_uses.saturating_sub(self.redeemed_by.len() as u32)
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| now >= expires_at)
+    }
+
+    // Not even the peers which joined with it may come back anymore
+    fn is_over(&self, now: u64, grace: Duration) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| now >= expires_at.saturating_add(grace.as_secs()))
+    }
+}
+
+/// Why an invite was not accepted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InviteError {
+    /// The token was never issued, it was revoked, or its grace period after expiry is over
+    Unknown,
+    /// Every use of the invite was taken by other peers
+    UsedUp,
+    /// The invite is past its expiry time
+    Expired,
+}
+
+impl std::fmt::Display for InviteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InviteError::Unknown => write!(f, "unknown or revoked invite"),
+            InviteError::UsedUp => write!(f, "invite was already used"),
+            InviteError::Expired => write!(f, "invite has expired"),
+        }
+    }
+}
+
+impl std::error::Error for InviteError {}
+
+/// Invites issued by a peer, shared by the peer and its listeners
+#[derive(Debug, Clone)]
+pub(crate) struct Invites {
+    invites: Arc<Mutex<HashMap<InviteToken, Invite>>>,
+    grace: Duration,
+}
+
+impl Invites {
+    pub(crate) fn new(grace: Duration) -> Self {
+        Invites {
+            invites: Arc::default(),
+            grace,
+        }
+    }
+
+    pub(crate) fn issue(&self, max_uses: u32, expires_at: Option<u64>) -> InviteToken {
+        let token = InviteToken::new();
+        self.prune().insert(
+            token,
+            Invite {
+                token,
+                max_uses,
+                redeemed_by: Vec::new(),
+                expires_at,
+            },
+        );
+        token
+    }
+
+    /// Outstanding invites, the expired and used up ones are left out; they are kept for the
+    /// grace period so the peers which joined with them can reconnect
+    pub(crate) fn list(&self) -> Vec<Invite> {
+        let now = unix_time();
+        self.prune()
+            .values()
+            .filter(|invite| !invite.is_expired(now) && invite.remaining_uses() > 0)
+            .cloned()
+            .collect()
+    }
+
+    pub(crate) fn revoke(&self, token: &InviteToken) -> bool {
+        self.lock().remove(token).is_some()
+    }
+
+    /// Whether `node_id` already joined with the invite and may still come back with it
+    pub(crate) fn redeemed(&self, token: &InviteToken, node_id: NodeId) -> bool {
+        self.prune()
+            .get(token)
+            .is_some_and(|invite| invite.redeemed_by.contains(&node_id))
+    }
+
+    /// Take a use of the invite for `node_id`, a peer which already joined with it is let in
+    /// again, even during the grace period after the expiry, so its sessions can reconnect
+    pub(crate) fn redeem(&self, token: &InviteToken, node_id: NodeId) -> Result<(), InviteError> {
+        let mut invites = self.prune();
+        let invite = invites.get_mut(token).ok_or(InviteError::Unknown)?;
+        if invite.redeemed_by.contains(&node_id) {
+            return Ok(());
+        }
+        if invite.is_expired(unix_time()) {
+            return Err(InviteError::Expired);
+        }
+        if invite.remaining_uses() == 0 {
+            return Err(InviteError::UsedUp);
+        }
+        invite.redeemed_by.push(node_id);
+        Ok(())
+    }
+
+    // Forget the invites past their grace period, then hand out the remaining ones
+    fn prune(&self) -> std::sync::MutexGuard<'_, HashMap<InviteToken, Invite>> {
+        let now = unix_time();
+        let mut invites = self.lock();
+        invites.retain(|_, invite| !invite.is_over(now, self.grace));
+        invites
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<InviteToken, Invite>> {
+        // The map is left consistent at every step, a panic elsewhere does not corrupt it
+        self.invites
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GRACE: Duration = Duration::from_secs(60);
+
+    fn node(seed: u8) -> NodeId {
+        iroh::SecretKey::from_bytes(&[seed; 32]).public()
+    }
+
+    // An invite redeemed by `node(1)` which expired `ago` seconds ago
+    fn expired_invite(invites: &Invites, ago: u64) -> InviteToken {
+        let token = invites.issue(2, None);
+        invites.redeem(&token, node(1)).unwrap();
+        invites.lock().get_mut(&token).unwrap().expires_at = Some(unix_time() - ago);
+        token
+    }
+
+    #[test]
+    fn uses_are_limited_to_different_peers() {
+        let invites = Invites::new(GRACE);
+        let token = invites.issue(1, None);
+        invites.redeem(&token, node(1)).unwrap();
+        invites.redeem(&token, node(1)).unwrap();
+        assert_eq!(invites.redeem(&token, node(2)), Err(InviteError::UsedUp));
+        assert!(invites.list().is_empty());
+    }
+
+    #[test]
+    fn joined_peers_rejoin_during_the_grace_period() {
+        let invites = Invites::new(GRACE);
+        let token = expired_invite(&invites, 10);
+        assert!(invites.redeemed(&token, node(1)));
+        invites.redeem(&token, node(1)).unwrap();
+        assert_eq!(invites.redeem(&token, node(2)), Err(InviteError::Expired));
+        assert!(invites.list().is_empty());
+    }
+
+    #[test]
+    fn invites_past_the_grace_period_are_forgotten() {
+        let invites = Invites::new(GRACE);
+        let token = expired_invite(&invites, GRACE.as_secs() + 10);
+        assert!(!invites.redeemed(&token, node(1)));
+        assert_eq!(invites.redeem(&token, node(1)), Err(InviteError::Unknown));
+        assert!(invites.lock().is_empty());
+    }
+
+    #[test]
+    fn revoked_invites_are_unknown() {
+        let invites = Invites::new(GRACE);
+        let token = invites.issue(1, None);
+        assert!(invites.revoke(&token));
+        assert_eq!(invites.redeem(&token, node(1)), Err(InviteError::Unknown));
+    }
+}
@@ -0,0 +1,179 @@
+use std::{collections::VecDeque, time::Duration};
+
+use tokio::time::Instant;
+
+// Pings still waiting for their pong, older ones are forgotten
+const MAX_OUTSTANDING_PINGS: usize = 16;
+
+/// Round trip time to the peer measured with heartbeats, smoothed as described in RFC 6298
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RttEstimate {
+    /// The most recent sample
+    pub latest: Duration,
+    /// Exponentially weighted moving average of the samples
+    pub smoothed: Duration,
+    /// Mean deviation of the samples from the smoothed value, i.e. the jitter
+    pub variance: Duration,
+}
+
+impl RttEstimate {
+    fn new(sample: Duration) -> Self {
+        RttEstimate {
+            latest: sample,
+            smoothed: sample,
+            variance: sample / 2,
+        }
+    }
+
+    fn update(&mut self, sample: Duration) {
+        let deviation = self.smoothed.abs_diff(sample);
+        self.variance = (self.variance * 3 + deviation) / 4;
+        self.smoothed = (self.smoothed * 7 + sample) / 8;
+        self.latest = sample;
+    }
+}
+
+/// Heartbeat bookkeeping shared by the keepalive and control loops of a connection
+pub(crate) struct Heartbeat {
+    next_nonce: u64,
+    outstanding: VecDeque<(u64, Instant)>,
+    last_pong: Instant,
+    rtt: Option<RttEstimate>,
+}
+
+impl Heartbeat {
+    pub(crate) fn new() -> Self {
+        Heartbeat {
+            next_nonce: 0,
+            outstanding: VecDeque::new(),
+            last_pong: Instant::now(),
+            rtt: None,
+        }
+    }
+
+    /// Register a ping about to be sent and return its nonce
+    pub(crate) fn ping(&mut self) -> u64 {
+        let nonce = self.next_nonce;
+        self.next_nonce += 1;
+        if self.outstanding.len() == MAX_OUTSTANDING_PINGS {
+            self.outstanding.pop_front();
+        }
+        self.outstanding.push_back((nonce, Instant::now()));
+        nonce
+    }
+
+    /// Take a pong into account, pongs to unknown pings are ignored
+    pub(crate) fn pong(&mut self, nonce: u64) {
+        let Some(position) = self.outstanding.iter().position(|(n, _)| *n == nonce) else {
+            return;
+        };
+        let (_, sent) = self.outstanding[position];
+        // Pongs come back in order, anything older will not be answered anymore
+        self.outstanding.drain(..=position);
+
+        let sample = sent.elapsed();
+        match &mut self.rtt {
+            Some(rtt) => rtt.update(sample),
+            None => self.rtt = Some(RttEstimate::new(sample)),
+        }
+        self.last_pong = Instant::now();
+    }
+
+    /// How long the peer has been silent
+    pub(crate) fn silence(&self) -> Duration {
+        self.last_pong.elapsed()
+    }
+
+    /// Heartbeats the peer did not answer since its last pong, one interval of grace is given
+    /// for the pong to travel back
+    pub(crate) fn missed(&self, interval: Duration) -> u32 {
+        if interval.is_zero() {
+            return 0;
+        }
+        let intervals = self.silence().as_millis() / interval.as_millis().max(1);
+        intervals.saturating_sub(1).min(u32::MAX as u128) as u32
+    }
+
+    pub(crate) fn rtt(&self) -> Option<RttEstimate> {
+        self.rtt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    #[test]
+    fn first_sample_seeds_the_estimate() {
+        let rtt = RttEstimate::new(100 * MS);
+        assert_eq!(rtt.latest, 100 * MS);
+        assert_eq!(rtt.smoothed, 100 * MS);
+        assert_eq!(rtt.variance, 50 * MS);
+    }
+
+    #[test]
+    fn samples_are_smoothed_as_in_rfc_6298() {
+        let mut rtt = RttEstimate::new(100 * MS);
+        rtt.update(180 * MS);
+        // RTTVAR = 3/4 * 50 + 1/4 * |100 - 180|, SRTT = 7/8 * 100 + 1/8 * 180
+        assert_eq!(rtt.variance, 57500 * Duration::from_micros(1));
+        assert_eq!(rtt.smoothed, 110 * MS);
+        assert_eq!(rtt.latest, 180 * MS);
+
+        // A steady round trip converges and the jitter fades away
+        for _ in 0..200 {
+            rtt.update(40 * MS);
+        }
+        assert!(rtt.smoothed.abs_diff(40 * MS) < MS);
+        assert!(rtt.variance < MS);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pongs_measure_the_round_trip() {
+        let mut heartbeat = Heartbeat::new();
+        let first = heartbeat.ping();
+        tokio::time::advance(30 * MS).await;
+        heartbeat.pong(first);
+        assert_eq!(heartbeat.rtt(), Some(RttEstimate::new(30 * MS)));
+
+        let second = heartbeat.ping();
+        tokio::time::advance(70 * MS).await;
+        heartbeat.pong(second);
+        let rtt = heartbeat.rtt().unwrap();
+        assert_eq!(rtt.latest, 70 * MS);
+        assert_eq!(rtt.smoothed, 35 * MS);
+        assert_eq!(rtt.variance, 21250 * Duration::from_micros(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stale_and_unknown_pongs_are_ignored() {
+        let mut heartbeat = Heartbeat::new();
+        let first = heartbeat.ping();
+        let second = heartbeat.ping();
+        tokio::time::advance(10 * MS).await;
+        heartbeat.pong(second);
+        let rtt = heartbeat.rtt();
+
+        // The older ping was forgotten once a newer one was answered
+        heartbeat.pong(first);
+        heartbeat.pong(second + 100);
+        assert_eq!(heartbeat.rtt(), rtt);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn missed_heartbeats_leave_one_interval_of_grace() {
+        let mut heartbeat = Heartbeat::new();
+        let interval = Duration::from_secs(1);
+        tokio::time::advance(Duration::from_millis(1500)).await;
+        assert_eq!(heartbeat.missed(interval), 0);
+        tokio::time::advance(Duration::from_secs(2)).await;
+        assert_eq!(heartbeat.missed(interval), 2);
+        assert_eq!(heartbeat.missed(Duration::ZERO), 0);
+
+        let nonce = heartbeat.ping();
+        heartbeat.pong(nonce);
+        assert_eq!(heartbeat.missed(interval), 0);
+    }
+}
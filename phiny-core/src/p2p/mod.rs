@@ -1,9 +1,53 @@
+mod access;
+mod admission;
+mod channel;
+mod chat;
+mod close;
 mod connection;
+mod contacts;
+mod control;
+mod handshake;
+mod identity;
+mod invite;
+mod keepalive;
 mod peer;
+mod presence;
+mod queue;
+mod room;
+mod sas;
+mod session;
+mod signaling;
+mod stats;
+mod status;
 mod ticket;
+mod transfer;
+mod transport;
 
 pub const ALPN: &[u8] = b"phiny/audiocall/0";
-pub use ticket::Ticket;
+pub use ticket::{TICKET_PREFIX, Ticket, TicketError};
 
+pub use access::{AccessDenied, AccessPolicy};
+pub use admission::{ListenerStats, RateLimit};
+pub use channel::{Channel, MessageReceiver};
+pub use chat::{Chat, ChatEvent, ChatMessage, ChatMessageId};
+pub use close::CloseCode;
 pub use connection::{Connection, Message};
-pub use peer::{ConnectionListener, Peer, PeerConfig};
+pub use contacts::{Contact, Contacts};
+pub use handshake::{
+    Capabilities, Codec, Features, HandshakeError, Hello, NegotiatedConfig, PROTOCOL_VERSION,
+};
+pub use identity::{
+    generate_secret_key, load_or_generate_secret_key, load_secret_key, save_secret_key,
+};
+pub use invite::{Invite, InviteError, InviteToken};
+pub use keepalive::RttEstimate;
+pub use peer::{ConnectionListener, DiscoveryMode, Peer, PeerConfig};
+pub use presence::{Presence, PresenceEvent, PresenceMember, PresenceState};
+pub use queue::{OverflowPolicy, SendPolicy};
+pub use room::{Room, RoomConfig, RoomEvent, RoomMember, RoomReceiver, RoomTopology};
+pub use sas::ShortAuthString;
+pub use session::{Session, SessionConfig, SessionEvent, SessionId, SessionListener};
+pub use signaling::{Call, CallConfig, CallDirection, CallEnd, CallState, SignalMessage};
+pub use stats::{ConnectionStats, PathChange};
+pub use status::ConnectionStatus;
+pub use transfer::{FileOffer, FileTransfer, IncomingFile, TransferProgress};
@@ -1,40 +1,212 @@
+use std::{
+    collections::HashMap,
+    net::{SocketAddrV4, SocketAddrV6},
+    sync::Arc,
+    time::Duration,
+};
+
 use super::ALPN;
-use crate::p2p::connection::Connection;
+use crate::p2p::{
+    access::{AccessDenied, AccessPolicy},
+    admission::{AdmissionCounters, ListenerStats, RateLimit, RateLimiter},
+    channel::Channel,
+    close::CloseCode,
+    connection::Connection,
+    handshake::{self, Capabilities, HandshakeError, Hello},
+    invite::{Invite, InviteToken, Invites},
+    queue::SendPolicy,
+    session::SessionId,
+    ticket::Ticket,
+};
 use anyhow::{Context as _, Result};
-use iroh::{Endpoint, NodeAddr};
-use tokio::sync::{mpsc, oneshot};
+use iroh::{
+    Endpoint, NodeAddr, NodeId, RelayMode, SecretKey, Watcher as _,
+    endpoint::{Connecting, ConnectionError, SendStream},
+};
+use iroh_gossip::{ALPN as GOSSIP_ALPN, net::Gossip};
+use log::debug;
+use tokio::{
+    sync::{Semaphore, mpsc, oneshot},
+    task::JoinHandle,
+};
+
+// How long a rejecting side waits for the rejection to be delivered before closing
+const REJECT_LINGER: Duration = Duration::from_secs(1);
+
+/// How a peer finds the addresses of the peers it connects to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DiscoveryMode {
+    /// Publish and resolve addresses through the n0 DNS servers, needs internet access
+    #[default]
+    N0,
+    /// Find peers on the local network with mDNS, works without internet access
+    LocalNetwork,
+    /// Both n0 and the local network
+    N0AndLocalNetwork,
+    /// No discovery, peers are only reached at the addresses given to [`Peer::connect`]
+    None,
+}
 
 #[derive(Debug, Clone)]
 pub struct PeerConfig {
+    /// Identity of the peer, a fresh one is generated when `None` so the [`NodeId`] changes on
+    /// every run; see [`load_or_generate_secret_key`](crate::p2p::load_or_generate_secret_key)
+    pub secret_key: Option<SecretKey>,
+    /// How addresses of other peers are found
+    pub discovery: DiscoveryMode,
+    /// Relay servers helping to establish connections, [`RelayMode::Disabled`] for direct
+    /// connections only
+    pub relay_mode: RelayMode,
+    /// Local addresses to bind to, a port of `0` (or `None`) picks a random port
+    pub bind_addr_v4: Option<SocketAddrV4>,
+    pub bind_addr_v6: Option<SocketAddrV6>,
     pub buffer_size: usize,
+    /// Incoming connections handled at once, handshakes in progress included; extra ones are
+    /// closed with [`CloseCode::Busy`] before the phiny handshake starts
     pub max_connections: usize,
+    /// Handshake attempts allowed from a single remote address, extra ones are refused
+    pub handshake_rate_limit: RateLimit,
+    /// Which peers may connect to us
+    pub access_policy: AccessPolicy,
+    /// Only accept connectors presenting an unexpired ticket signed by us, see
+    /// [`Peer::sign_ticket`]; presented tickets are checked either way
+    pub require_ticket: bool,
+    /// Only accept connectors presenting a ticket with a valid invite, see [`Peer::invite`]
+    pub require_invite: bool,
+    /// How long after an invite expired the peers which joined with it may still reconnect with
+    /// it, e.g. to resume their session; the invite is forgotten afterwards
+    pub invite_grace: Duration,
+    /// Run the gossip protocol, used by rooms to find their members; [`Peer::listen`] accepts
+    /// gossip connections from peers the access policy allows, up to `max_connections` of them
+    /// on top of the call connections
+    pub gossip: bool,
+    /// Name shown to the remote peer during the handshake
+    pub display_name: Option<String>,
+    /// Codecs, frame sizes, sample rates and features offered during the handshake
+    pub capabilities: Capabilities,
+    /// Time allowed for the remote peer to complete the handshake
+    pub handshake_timeout: Duration,
+    /// Largest message payload accepted from (and sent to) the remote peer, in bytes
+    pub max_frame_size: usize,
+    /// Bytes allowed to wait on a channel of the reliable stream until they are received, the
+    /// connection is closed with [`CloseCode::ProtocolError`] past it; raised to `max_frame_size` when lower
+    pub max_receive_buffer: usize,
+    /// How [`Connection::send`] queues the messages of each channel, channels not listed block
+    /// while the queue is full
+    pub send_policies: HashMap<Channel, SendPolicy>,
+    /// Time allowed for the remote peer to acknowledge a graceful close
+    pub close_timeout: Duration,
+    /// How often a heartbeat is sent to measure the round trip time and check liveness, zero
+    /// disables heartbeats
+    pub keepalive_interval: Duration,
+    /// How long the remote peer may leave heartbeats unanswered before the connection is
+    /// declared dead
+    pub keepalive_timeout: Duration,
 }
 
 impl Default for PeerConfig {
     fn default() -> Self {
         PeerConfig {
+            secret_key: None,
+            discovery: DiscoveryMode::N0,
+            relay_mode: RelayMode::Default,
+            bind_addr_v4: None,
+            bind_addr_v6: None,
             buffer_size: 40,
-            max_connections: 1,
+            // Leaves room for a session resuming while its old connection is still around
+            max_connections: 4,
+            handshake_rate_limit: RateLimit::default(),
+            access_policy: AccessPolicy::AllowAll,
+            require_ticket: false,
+            require_invite: false,
+            invite_grace: Duration::from_secs(60 * 60),
+            gossip: false,
+            display_name: None,
+            capabilities: Capabilities::default(),
+            handshake_timeout: Duration::from_secs(10),
+            max_frame_size: 1024 * 1024,
+            max_receive_buffer: 8 * 1024 * 1024,
+            send_policies: HashMap::new(),
+            close_timeout: Duration::from_secs(2),
+            keepalive_interval: Duration::from_secs(1),
+            keepalive_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl PeerConfig {
+    fn hello(&self, session: Option<SessionId>, ticket: Option<String>) -> Hello {
+        Hello {
+            display_name: self.display_name.clone(),
+            capabilities: self.capabilities.clone(),
+            session,
+            ticket,
         }
     }
 }
 
 /// Represents a peer in the p2p network
+#[derive(Clone)]
 pub struct Peer {
     endpoint: Endpoint,
     config: PeerConfig,
+    invites: Invites,
+    gossip: Option<Gossip>,
 }
 
 impl Peer {
     /// Create a new peer with the given configuration
     pub async fn new(config: PeerConfig) -> Result<Self> {
-        let endpoint = Endpoint::builder()
-            .discovery_n0()
-            .alpns(vec![super::ALPN.to_vec()])
-            .bind()
-            .await?;
+        let mut alpns = vec![super::ALPN.to_vec()];
+        if config.gossip {
+            alpns.push(GOSSIP_ALPN.to_vec());
+        }
+        let mut builder = Endpoint::builder()
+            .alpns(alpns)
+            .relay_mode(config.relay_mode.clone());
+        builder = match config.discovery {
+            DiscoveryMode::N0 => builder.discovery_n0(),
+            DiscoveryMode::LocalNetwork => builder.discovery_local_network(),
+            DiscoveryMode::N0AndLocalNetwork => builder.discovery_n0().discovery_local_network(),
+            DiscoveryMode::None => builder,
+        };
+        if let Some(secret_key) = &config.secret_key {
+            builder = builder.secret_key(secret_key.clone());
+        }
+        if let Some(addr) = config.bind_addr_v4 {
+            builder = builder.bind_addr_v4(addr);
+        }
+        if let Some(addr) = config.bind_addr_v6 {
+            builder = builder.bind_addr_v6(addr);
+        }
+        let endpoint = builder.bind().await?;
+        let gossip = config
+            .gossip
+            .then(|| Gossip::builder().spawn(endpoint.clone()));
+
+        Ok(Self {
+            endpoint,
+            invites: Invites::new(config.invite_grace),
+            config,
+            gossip,
+        })
+    }
+
+    pub(crate) fn config(&self) -> &PeerConfig {
+        &self.config
+    }
 
-        Ok(Self { endpoint, config })
+    pub(crate) fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+
+    pub(crate) fn gossip(&self) -> Option<&Gossip> {
+        self.gossip.as_ref()
+    }
+
+    /// The identity of this peer, stable across runs when [`PeerConfig::secret_key`] is set
+    pub fn node_id(&self) -> NodeId {
+        self.endpoint.node_id()
     }
 
     /// Get the address of this peer
@@ -42,24 +214,112 @@ impl Peer {
         self.endpoint.node_addr()
     }
 
+    /// Wait until this peer knows an address it can be reached at and return it
+    ///
+    /// Right after [`Peer::new`] the address may have neither a relay nor any direct address
+    /// yet, which makes a useless ticket when discovery is off.
+    pub async fn reachable_address(&self) -> NodeAddr {
+        let mut addr = self.endpoint.watch_node_addr();
+        loop {
+            let current = addr.get();
+            if !current.is_empty() {
+                return current;
+            }
+            if addr.updated().await.is_err() {
+                return addr.get();
+            }
+        }
+    }
+
+    /// Sign a ticket pointing to this peer, so listeners requiring tickets let its holder in
+    pub fn sign_ticket(&self, ticket: Ticket) -> Result<Ticket> {
+        Ok(ticket.sign(self.endpoint.secret_key())?)
+    }
+
+    /// Turn a ticket pointing to this peer into a signed invite letting `max_uses` different
+    /// peers in, until the ticket expires or the invite is revoked
+    ///
+    /// A peer which joined with the invite may reconnect with it, even once it expired, e.g. to
+    /// resume its session.
+    pub fn invite(&self, mut ticket: Ticket, max_uses: u32) -> Result<Ticket> {
+        ticket.invite = Some(self.invites.issue(max_uses, ticket.expires_at));
+        self.sign_ticket(ticket)
+    }
+
+    /// Invites which were neither used up, revoked nor expired
+    pub fn invites(&self) -> Vec<Invite> {
+        self.invites.list()
+    }
+
+    /// Stop accepting an invite, peers which joined with it can no longer reconnect with it;
+    /// false if there was no such invite
+    pub fn revoke_invite(&self, token: &InviteToken) -> bool {
+        self.invites.revoke(token)
+    }
+
     /// Connect to another peer
     pub async fn connect(&self, addr: NodeAddr) -> Result<Connection> {
+        self.connect_with_session(&Ticket::new(addr), None).await
+    }
+
+    /// Connect to the peer a ticket points to, presenting the ticket if it is signed
+    pub async fn connect_ticket(&self, ticket: &Ticket) -> Result<Connection> {
+        self.connect_with_session(ticket, None).await
+    }
+
+    // Connect to another peer, announcing the session the connection belongs to
+    pub(crate) async fn connect_with_session(
+        &self,
+        ticket: &Ticket,
+        session: Option<SessionId>,
+    ) -> Result<Connection> {
+        // An unsigned ticket proves nothing, there is no point in presenting it
+        let presented = match ticket.is_signed() {
+            true => Some(ticket.encode()?),
+            false => None,
+        };
         let conn = self
             .endpoint
-            .connect(addr, ALPN)
+            .connect(ticket.node_addrs.clone(), ALPN)
             .await
             .context("Failed to connect to peer")?;
 
-        let (mut send, recv) = conn
+        let (mut send, mut recv) = conn
             .open_bi()
             .await
             .context("Failed to open bidirectional stream")?;
 
-        send.write_all(b"PHINY_HANDSHAKE_V1")
-            .await
-            .context("Failed to send handshake")?;
+        let handshake = tokio::time::timeout(
+            self.config.handshake_timeout,
+            handshake::initiate(&mut send, &mut recv, &self.config.hello(session, presented)),
+        )
+        .await
+        .context("Timed out waiting for the handshake")
+        .flatten();
 
-        Ok(Connection::new(send, recv, self.config.buffer_size))
+        match handshake {
+            Ok((remote, negotiated)) => Connection::new(
+                &self.endpoint,
+                conn,
+                send,
+                recv,
+                &self.config,
+                remote,
+                negotiated,
+            ),
+            Err(e) => {
+                // The listener may have rejected us by closing the connection with a reason
+                if let Some(ConnectionError::ApplicationClosed(close)) = conn.close_reason() {
+                    return Err(e.context(format!(
+                        "Peer closed the connection ({}): {}",
+                        CloseCode::from_varint(close.error_code),
+                        String::from_utf8_lossy(&close.reason)
+                    )));
+                }
+                reject(&conn, send, &e).await;
+                Err(e)
+            }
+        }
     }
 
     /// Listen for incoming connections
@@ -69,49 +329,113 @@ impl Peer {
 
         // Clone the endpoint for the background task
         let endpoint = self.endpoint.clone();
-        let buffer_size = self.config.buffer_size;
+        let config = self.config.clone();
+        let invites = self.invites.clone();
+        let gossip = self.gossip.clone();
+        let slots = Arc::new(Semaphore::new(self.config.max_connections));
+        // Gossip connections are bounded apart, so they never take the slot of a call
+        let gossip_slots = Arc::new(Semaphore::new(self.config.max_connections));
+        let counters = Arc::new(AdmissionCounters::default());
+        // Bounds the connections only accepted to be closed as busy
+        let busy_slots = Arc::new(Semaphore::new(self.config.max_connections));
+        let accept_slots = Arc::clone(&slots);
+        let accept_counters = Arc::clone(&counters);
 
         // Spawn a task to accept incoming connections
-        tokio::spawn(async move {
+        let accept_task = tokio::spawn(async move {
+            let mut rate_limiter = RateLimiter::new(config.handshake_rate_limit);
             loop {
                 tokio::select! {
                     Some(incoming) = endpoint.accept() => {
+                        let remote = incoming.remote_address();
+                        if !rate_limiter.allow(remote.ip()) {
+                            debug!("Refusing connection from {}, too many attempts", remote);
+                            AdmissionCounters::increment(&accept_counters.rejected_rate_limited);
+                            incoming.refuse();
+                            continue;
+                        }
+
+                        // Without a free slot the handshake is not even started; the ALPN is
+                        // not known yet, so a slot of either kind lets the connection in
+                        let slot = Arc::clone(&accept_slots).try_acquire_owned().ok();
+                        let gossip_slot = gossip
+                            .as_ref()
+                            .and_then(|_| Arc::clone(&gossip_slots).try_acquire_owned().ok());
+                        if slot.is_none() && gossip_slot.is_none() {
+                            AdmissionCounters::increment(&accept_counters.rejected_busy);
+                            // Tell the remote why, unless too many are already being told
+                            let Ok(busy_slot) = Arc::clone(&busy_slots).try_acquire_owned() else {
+                                debug!("Refusing connection from {}, every slot is taken", remote);
+                                incoming.refuse();
+                                continue;
+                            };
+                            debug!("Closing connection from {}, every slot is taken", remote);
+                            let timeout = config.handshake_timeout;
+                            tokio::spawn(async move {
+                                if let Ok(connecting) = incoming.accept() {
+                                    close_busy(connecting, timeout).await;
+                                }
+                                drop(busy_slot);
+                            });
+                            continue;
+                        }
+
                         let connections_tx = connections_tx.clone();
+                        let config = config.clone();
+                        let endpoint = endpoint.clone();
+                        let invites = invites.clone();
+                        let gossip = gossip.clone();
+                        let counters = Arc::clone(&accept_counters);
 
                         tokio::spawn(async move {
-                            match incoming.await {
-                                Ok(connection) => {
-                                    // Accept a bidirectional stream
-                                    match connection.accept_bi().await {
-                                        Ok((send, mut recv)) => {
-                                            let mut handshake = [0u8; 18]; // "PHINY_HANDSHAKE_V1"
-                                            match recv.read_exact(&mut handshake).await {
-                                                Ok(_) => {
-                                                    // Create a Connection object
-                                                    let connection = Connection::new(send, recv, buffer_size);
-
-                                                    // Send the connection through the channel
-                                                    if connections_tx.send(Ok(connection)).await.is_err() {
-                                                        // Channel closed, listener was dropped
-                                                        return;
-                                                    }
-                                                },
-                                                Err(e) => {
-                                                    // Failed to read handshake
-                                                    let _ = connections_tx.send(Err(anyhow::anyhow!("Failed to read handshake: {}", e))).await;
-                                                }
-                                            }
-                                        },
-                                        Err(e) => {
-                                            // Failed to accept bidirectional stream
-                                            let _ = connections_tx.send(Err(anyhow::anyhow!("Failed to accept bidirectional stream: {}", e))).await;
-                                        }
-                                    }
-                                },
+                            let mut connecting = match incoming.accept() {
+                                Ok(connecting) => connecting,
                                 Err(e) => {
-                                    // Failed to accept connection
-                                    let _ = connections_tx.send(Err(anyhow::anyhow!("Failed to accept connection: {}", e))).await;
+                                    debug!("Failed to accept connection from {}: {}", remote, e);
+                                    AdmissionCounters::increment(&counters.failed_handshakes);
+                                    return;
                                 }
+                            };
+                            if let Some(gossip) = &gossip
+                                && connecting.alpn().await.is_ok_and(|alpn| alpn == GOSSIP_ALPN)
+                            {
+                                drop(slot);
+                                let Some(gossip_slot) = gossip_slot else {
+                                    debug!("Closing gossip from {}, every gossip slot is taken", remote);
+                                    AdmissionCounters::increment(&counters.rejected_busy);
+                                    close_busy(connecting, config.handshake_timeout).await;
+                                    return;
+                                };
+                                accept_gossip(connecting, gossip, &config, &counters).await;
+                                drop(gossip_slot);
+                                return;
+                            }
+                            drop(gossip_slot);
+
+                            let Some(slot) = slot else {
+                                debug!("Closing connection from {}, every slot is taken", remote);
+                                AdmissionCounters::increment(&counters.rejected_busy);
+                                close_busy(connecting, config.handshake_timeout).await;
+                                return;
+                            };
+
+                            let result = accept_connection(connecting, &endpoint, &config, &invites).await;
+                            match &result {
+                                Ok(connection) => {
+                                    AdmissionCounters::increment(&counters.accepted);
+                                    // The slot is freed once the connection is gone
+                                    connection.hold_until_closed(slot);
+                                }
+                                Err(e) if e.is::<AccessDenied>() => {
+                                    AdmissionCounters::increment(&counters.rejected_access_denied)
+                                }
+                                Err(_) => AdmissionCounters::increment(&counters.failed_handshakes),
+                            }
+                            // Error only when the channel is closed, i.e. the listener was closed
+                            if let Err(mpsc::error::SendError(Ok(connection))) =
+                                connections_tx.send(result).await
+                            {
+                                let _ = connection.close(CloseCode::GoingAway, "listener closed").await;
                             }
                         });
                     },
@@ -124,15 +448,171 @@ impl Peer {
 
         Ok(ConnectionListener {
             connections: connections_rx,
-            _close_signal: close_tx,
+            close_signal: close_tx,
+            accept_task,
+            slots,
+            max_connections: self.config.max_connections,
+            counters,
         })
     }
 }
 
+// Complete the QUIC handshake only to tell the remote we are at our connection limit, before
+// any phiny handshake
+async fn close_busy(connecting: Connecting, timeout: Duration) {
+    if let Ok(Ok(connection)) = tokio::time::timeout(timeout, connecting).await {
+        connection.close(CloseCode::Busy.to_varint(), b"too many connections");
+    }
+}
+
+// Hand an incoming gossip connection to the gossip protocol once the access policy allowed
+// its peer, returns when the connection is gone
+async fn accept_gossip(
+    connecting: Connecting,
+    gossip: &Gossip,
+    config: &PeerConfig,
+    counters: &AdmissionCounters,
+) {
+    let connection = match connecting.await {
+        Ok(connection) => connection,
+        Err(e) => {
+            debug!("Failed to accept gossip: {}", e);
+            AdmissionCounters::increment(&counters.failed_handshakes);
+            return;
+        }
+    };
+    let Ok(node_id) = connection.remote_node_id() else {
+        AdmissionCounters::increment(&counters.failed_handshakes);
+        return;
+    };
+    if !config.access_policy.allows(node_id).await {
+        debug!("Closing gossip from {}, access denied", node_id);
+        AdmissionCounters::increment(&counters.rejected_access_denied);
+        connection.close(CloseCode::AccessDenied.to_varint(), b"access denied");
+        return;
+    }
+    if let Err(e) = gossip.handle_connection(connection.clone()).await {
+        debug!("Gossip failed to take the connection of {}: {}", node_id, e);
+        return;
+    }
+    connection.closed().await;
+}
+
+// Accept the incoming connection and run the listening side of the handshake
+async fn accept_connection(
+    connecting: Connecting,
+    endpoint: &Endpoint,
+    config: &PeerConfig,
+    invites: &Invites,
+) -> Result<Connection> {
+    let connection = connecting.await.context("Failed to accept connection")?;
+
+    // QUIC authenticated the peer, check it may talk to us before going any further
+    let node_id = connection
+        .remote_node_id()
+        .context("Peer did not present its node id")?;
+    if !config.access_policy.allows(node_id).await {
+        debug!("Closing connection from {}, access denied", node_id);
+        connection.close(CloseCode::AccessDenied.to_varint(), b"access denied");
+        return Err(AccessDenied(node_id).into());
+    }
+
+    // Accept a bidirectional stream
+    let (mut send, mut recv) = connection
+        .accept_bi()
+        .await
+        .context("Failed to accept bidirectional stream")?;
+
+    let handshake = tokio::time::timeout(
+        config.handshake_timeout,
+        handshake::respond(&mut send, &mut recv, &config.hello(None, None), |remote| {
+            check_ticket(remote, node_id, endpoint.node_id(), config, invites)
+        }),
+    )
+    .await
+    .context("Timed out waiting for the handshake")
+    .flatten();
+
+    match handshake {
+        Ok((remote, negotiated)) => {
+            Connection::new(endpoint, connection, send, recv, config, remote, negotiated)
+        }
+        Err(e) => {
+            reject(&connection, send, &e).await;
+            Err(e.context("Handshake with the remote peer failed"))
+        }
+    }
+}
+
+// Check the ticket the connector presented was issued by us and is still valid, taking a use
+// of its invite; runs once the handshake negotiated a configuration
+fn check_ticket(
+    remote: &Hello,
+    remote_node_id: NodeId,
+    local: NodeId,
+    config: &PeerConfig,
+    invites: &Invites,
+) -> Result<(), HandshakeError> {
+    let Some(encoded) = &remote.ticket else {
+        return match config.require_ticket || config.require_invite {
+            true => Err(HandshakeError::Unauthorized(
+                "a signed ticket is required".to_string(),
+            )),
+            false => Ok(()),
+        };
+    };
+
+    let unauthorized = |e: &dyn std::fmt::Display| HandshakeError::Unauthorized(e.to_string());
+    let ticket = Ticket::decode(encoded).map_err(|e| unauthorized(&e))?;
+    if ticket.node_addrs.node_id != local {
+        return Err(unauthorized(&"ticket was issued by another peer"));
+    }
+    ticket.verify_signature().map_err(|e| unauthorized(&e))?;
+    // A peer which joined with the invite may come back for a while after the ticket expired,
+    // e.g. to resume its session
+    let rejoining = ticket
+        .invite
+        .is_some_and(|token| invites.redeemed(&token, remote_node_id));
+    if !rejoining {
+        ticket.validate().map_err(|e| unauthorized(&e))?;
+    }
+
+    match ticket.invite {
+        Some(token) => invites
+            .redeem(&token, remote_node_id)
+            .map_err(|e| unauthorized(&e)),
+        None if config.require_invite => Err(unauthorized(&"an invite is required")),
+        None => Ok(()),
+    }
+}
+
+// Close the connection after a failed handshake, giving the remote a moment to read our
+// rejection before the QUIC connection goes away
+async fn reject(
+    connection: &iroh::endpoint::Connection,
+    mut send: SendStream,
+    error: &anyhow::Error,
+) {
+    if send.finish().is_ok() {
+        let _ = tokio::time::timeout(REJECT_LINGER, send.stopped()).await;
+    }
+    connection.close(
+        CloseCode::HandshakeFailed.to_varint(),
+        error.to_string().as_bytes(),
+    );
+}
+
 /// Listener for incoming connections
+///
+/// Dropping the listener stops accepting new connections, [`ConnectionListener::close`] also
+/// gracefully closes connections which were accepted but not taken yet.
 pub struct ConnectionListener {
     connections: mpsc::Receiver<Result<Connection>>,
-    _close_signal: oneshot::Sender<()>,
+    close_signal: oneshot::Sender<()>,
+    accept_task: JoinHandle<()>,
+    slots: Arc<Semaphore>,
+    max_connections: usize,
+    counters: Arc<AdmissionCounters>,
 }
 
 impl ConnectionListener {
@@ -144,8 +624,36 @@ impl ConnectionListener {
         }
     }
 
+    /// What the listener let in and kept out so far
+    pub fn stats(&self) -> ListenerStats {
+        self.counters.snapshot(
+            self.max_connections - self.slots.available_permits(),
+            self.max_connections,
+        )
+    }
+
     /// Stop listening for connections
-    pub fn close(self) {
-        // Dropping the close_signal will signal the background task to shut down
+    ///
+    /// Connections still waiting to be accepted are closed with [`CloseCode::GoingAway`].
+    pub async fn close(self) {
+        let ConnectionListener {
+            mut connections,
+            close_signal,
+            accept_task,
+            ..
+        } = self;
+
+        let _ = close_signal.send(());
+        let _ = accept_task.await;
+
+        // Handshakes still in flight see the closed channel and close their connection
+        connections.close();
+        while let Some(result) = connections.recv().await {
+            if let Ok(connection) = result {
+                let _ = connection
+                    .close(CloseCode::GoingAway, "listener closed")
+                    .await;
+            }
+        }
     }
 }
@@ -0,0 +1,491 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex as StdMutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Result, anyhow, bail};
+use bincode::{Decode, Encode};
+use iroh::{NodeAddr, NodeId};
+use iroh_base::Signature;
+use iroh_gossip::api::{Event, GossipReceiver, GossipSender};
+use log::debug;
+use n0_future::StreamExt as _;
+use tokio::{
+    select,
+    sync::{Notify, broadcast, watch},
+    task::JoinHandle,
+};
+
+use crate::p2p::{peer::Peer, ticket::Ticket};
+
+// How often we tell the room we are still there
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5);
+// A member which was not heard of for this long is considered gone
+const MEMBER_TIMEOUT: Duration = Duration::from_secs(15);
+// Time allowed for the announcement of our departure to go out
+const LEAVE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// What a member of a room is up to, as announced on the room's gossip topic
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub enum PresenceState {
+    InCall,
+    Muted,
+    Left,
+}
+
+/// A member of a room as it announced itself
+#[derive(Debug, Clone)]
+pub struct PresenceMember {
+    pub node_id: NodeId,
+    /// Its signed room ticket, holding its addresses and name
+    pub ticket: Ticket,
+    pub state: PresenceState,
+}
+
+/// Changes of the announced members of a room
+#[derive(Debug, Clone)]
+pub enum PresenceEvent {
+    Joined(PresenceMember),
+    StateChanged(PresenceMember),
+    /// The member announced it left, or was not heard of for a while
+    Left(NodeId),
+}
+
+#[derive(Encode, Decode)]
+struct Announcement {
+    ticket: String,
+    state: PresenceState,
+    /// Increases with every announcement of the sender, older ones are ignored
+    sequence: u64,
+}
+
+// Gossip messages are forwarded by any member, the sender signs what it announces
+#[derive(Encode, Decode)]
+struct SignedAnnouncement {
+    node_id: [u8; 32],
+    announcement: Vec<u8>,
+    signature: [u8; 64],
+}
+
+struct Announced {
+    member: PresenceMember,
+    sequence: u64,
+    last_seen: Instant,
+}
+
+// The last sequence of a member which left or went silent, replayed older announcements must
+// not bring it back
+struct Departed {
+    sequence: u64,
+    at: Instant,
+}
+
+struct Inner {
+    room: String,
+    peer: Peer,
+    // Locked before `departed` when both are needed
+    members: StdMutex<HashMap<NodeId, Announced>>,
+    departed: StdMutex<HashMap<NodeId, Departed>>,
+    events: broadcast::Sender<PresenceEvent>,
+}
+
+/// Membership of a room spread over a gossip topic derived from the room id
+///
+/// Every member periodically announces its signed room ticket and state, so a joiner only
+/// needs the address of one member to learn about all the others. Needs a peer created with
+/// [`PeerConfig::gossip`](crate::p2p::PeerConfig::gossip) and listening, as other members
+/// connect to it. [`Room`](crate::p2p::Room) runs one when gossip is enabled.
+pub struct Presence {
+    inner: Arc<Inner>,
+    state: watch::Sender<PresenceState>,
+    announce_task: JoinHandle<()>,
+    receive_task: JoinHandle<()>,
+}
+
+impl Presence {
+    /// Join the topic of the room `ticket` names, announcing the ticket
+    ///
+    /// `ticket` must be signed by `peer` and carry the room id as its call id. `bootstrap` are
+    /// the addresses of members already in the room, empty when opening it.
+    pub async fn join(
+        peer: &Peer,
+        ticket: &Ticket,
+        state: PresenceState,
+        bootstrap: Vec<NodeAddr>,
+    ) -> Result<Self> {
+        let gossip = peer
+            .gossip()
+            .ok_or_else(|| anyhow!("Gossip is not enabled on this peer"))?;
+        let room = ticket
+            .call_id
+            .clone()
+            .ok_or_else(|| anyhow!("Ticket does not point to a room"))?;
+        if ticket.node_addrs.node_id != peer.node_id() {
+            bail!("Ticket does not point to this peer");
+        }
+        ticket.verify_signature()?;
+
+        let mut bootstrap_ids = Vec::with_capacity(bootstrap.len());
+        for addr in bootstrap {
+            if addr.node_id == peer.node_id() {
+                continue;
+            }
+            bootstrap_ids.push(addr.node_id);
+            add_address(peer, addr);
+        }
+        let (sender, receiver) = gossip
+            .subscribe(topic_id(&room), bootstrap_ids)
+            .await?
+            .split();
+
+        let (events, _) = broadcast::channel(16);
+        let inner = Arc::new(Inner {
+            room,
+            peer: peer.clone(),
+            members: StdMutex::new(HashMap::new()),
+            departed: StdMutex::new(HashMap::new()),
+            events,
+        });
+        let state = watch::Sender::new(state);
+        let neighbor_up = Arc::new(Notify::new());
+
+        let announce_task = tokio::spawn(Arc::clone(&inner).announce(
+            sender,
+            ticket.encode()?,
+            state.subscribe(),
+            Arc::clone(&neighbor_up),
+        ));
+        let receive_task = tokio::spawn(Arc::clone(&inner).receive(receiver, neighbor_up));
+
+        Ok(Presence {
+            inner,
+            state,
+            announce_task,
+            receive_task,
+        })
+    }
+
+    /// The other members announced on the topic
+    pub fn members(&self) -> Vec<PresenceMember> {
+        self.inner
+            .members
+            .lock()
+            .unwrap()
+            .values()
+            .map(|announced| announced.member.clone())
+            .collect()
+    }
+
+    /// Subscribe to members joining, leaving and changing their state
+    pub fn events(&self) -> broadcast::Receiver<PresenceEvent> {
+        self.inner.events.subscribe()
+    }
+
+    pub fn state(&self) -> PresenceState {
+        *self.state.borrow()
+    }
+
+    /// Announce a new state right away
+    pub fn set_state(&self, state: PresenceState) {
+        self.state.send_replace(state);
+    }
+
+    /// Announce we are leaving and stop following the topic
+    pub async fn leave(mut self) {
+        self.receive_task.abort();
+        self.state.send_replace(PresenceState::Left);
+        let _ = tokio::time::timeout(LEAVE_TIMEOUT, &mut self.announce_task).await;
+    }
+}
+
+impl Drop for Presence {
+    fn drop(&mut self) {
+        self.announce_task.abort();
+        self.receive_task.abort();
+    }
+}
+
+impl Inner {
+    // Announce ourselves periodically, on state changes and to new neighbors, until we left
+    async fn announce(
+        self: Arc<Self>,
+        sender: GossipSender,
+        ticket: String,
+        mut state: watch::Receiver<PresenceState>,
+        neighbor_up: Arc<Notify>,
+    ) {
+        // Counting from the current time, announcements made before a restart stay older
+        let mut sequence = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let mut interval = tokio::time::interval(ANNOUNCE_INTERVAL);
+        loop {
+            select! {
+                _ = interval.tick() => {}
+                changed = state.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                }
+                _ = neighbor_up.notified() => {}
+            }
+            sequence += 1;
+            let current = *state.borrow_and_update();
+            match self.sign(&ticket, current, sequence) {
+                Ok(message) => {
+                    if let Err(e) = sender.broadcast(message.into()).await {
+                        debug!("Failed to announce ourselves in room {}: {}", self.room, e);
+                    }
+                }
+                Err(e) => debug!("Failed to sign our announcement: {}", e),
+            }
+            if current == PresenceState::Left {
+                return;
+            }
+        }
+    }
+
+    fn sign(&self, ticket: &str, state: PresenceState, sequence: u64) -> Result<Vec<u8>> {
+        let announcement = bincode::encode_to_vec(
+            Announcement {
+                ticket: ticket.to_string(),
+                state,
+                sequence,
+            },
+            bincode::config::standard(),
+        )?;
+        let signature = self
+            .peer
+            .endpoint()
+            .secret_key()
+            .sign(&announcement)
+            .to_bytes();
+        Ok(bincode::encode_to_vec(
+            SignedAnnouncement {
+                node_id: *self.peer.node_id().as_bytes(),
+                announcement,
+                signature,
+            },
+            bincode::config::standard(),
+        )?)
+    }
+
+    // Follow the announcements of the other members, forgetting the silent ones
+    async fn receive(self: Arc<Self>, mut receiver: GossipReceiver, neighbor_up: Arc<Notify>) {
+        let mut expiry = tokio::time::interval(ANNOUNCE_INTERVAL);
+        loop {
+            let event = select! {
+                event = receiver.next() => event,
+                _ = expiry.tick() => {
+                    self.expire();
+                    continue;
+                }
+            };
+            match event {
+                Some(Ok(Event::Received(message))) => {
+                    if let Err(e) = self.handle(&message.content) {
+                        debug!(
+                            "Ignoring announcement delivered by {}: {}",
+                            message.delivered_from, e
+                        );
+                    }
+                }
+                // Whoever just connected to the topic learns about us without waiting
+                Some(Ok(Event::NeighborUp(_))) => neighbor_up.notify_one(),
+                Some(Ok(Event::NeighborDown(_))) => {}
+                Some(Ok(Event::Lagged)) => debug!("Missed announcements in room {}", self.room),
+                Some(Err(e)) => {
+                    debug!("Gossip of room {} failed: {}", self.room, e);
+                    return;
+                }
+                None => return,
+            }
+        }
+    }
+
+    fn handle(&self, message: &[u8]) -> Result<()> {
+        let (signed, _): (SignedAnnouncement, _) =
+            bincode::decode_from_slice(message, bincode::config::standard())?;
+        let node_id = NodeId::from_bytes(&signed.node_id)?;
+        node_id
+            .verify(
+                &signed.announcement,
+                &Signature::from_bytes(&signed.signature),
+            )
+            .map_err(|_| anyhow!("announcement of {} has an invalid signature", node_id))?;
+        let (announcement, _): (Announcement, _) =
+            bincode::decode_from_slice(&signed.announcement, bincode::config::standard())?;
+
+        let ticket = Ticket::decode(&announcement.ticket)?;
+        if ticket.node_addrs.node_id != node_id {
+            bail!("{} announced the ticket of another peer", node_id);
+        }
+        if ticket.call_id.as_deref() != Some(self.room.as_str()) {
+            bail!("{} announced a ticket of another room", node_id);
+        }
+        if node_id == self.peer.node_id() {
+            return Ok(());
+        }
+
+        let member = PresenceMember {
+            node_id,
+            ticket,
+            state: announcement.state,
+        };
+        let mut members = self.members.lock().unwrap();
+        let mut departed = self.departed.lock().unwrap();
+        let last_sequence = members
+            .get(&node_id)
+            .map(|announced| announced.sequence)
+            .or_else(|| departed.get(&node_id).map(|departed| departed.sequence));
+        if last_sequence.is_some_and(|sequence| sequence >= announcement.sequence) {
+            // Announcements may arrive out of order, or be replayed
+            return Ok(());
+        }
+        if member.state == PresenceState::Left {
+            departed.insert(
+                node_id,
+                Departed {
+                    sequence: announcement.sequence,
+                    at: Instant::now(),
+                },
+            );
+            if members.remove(&node_id).is_some() {
+                let _ = self.events.send(PresenceEvent::Left(node_id));
+            }
+            return Ok(());
+        }
+        departed.remove(&node_id);
+        drop(departed);
+
+        add_address(&self.peer, member.ticket.node_addrs.clone());
+        let event = match members.get(&node_id) {
+            None => Some(PresenceEvent::Joined(member.clone())),
+            Some(announced) if announced.member.state != member.state => {
+                Some(PresenceEvent::StateChanged(member.clone()))
+            }
+            Some(_) => None,
+        };
+        members.insert(
+            node_id,
+            Announced {
+                member,
+                sequence: announcement.sequence,
+                last_seen: Instant::now(),
+            },
+        );
+        if let Some(event) = event {
+            let _ = self.events.send(event);
+        }
+        Ok(())
+    }
+
+    fn expire(&self) {
+        let mut members = self.members.lock().unwrap();
+        let mut departed = self.departed.lock().unwrap();
+        // By now every announcement older than the departure has died out
+        departed.retain(|_, departed| departed.at.elapsed() < MEMBER_TIMEOUT);
+        let expired: Vec<_> = members
+            .iter()
+            .filter(|(_, announced)| announced.last_seen.elapsed() >= MEMBER_TIMEOUT)
+            .map(|(node_id, _)| *node_id)
+            .collect();
+        for node_id in expired {
+            debug!("{} was not heard of in room {}", node_id, self.room);
+            if let Some(announced) = members.remove(&node_id) {
+                departed.insert(
+                    node_id,
+                    Departed {
+                        sequence: announced.sequence,
+                        at: Instant::now(),
+                    },
+                );
+            }
+            let _ = self.events.send(PresenceEvent::Left(node_id));
+        }
+    }
+}
+
+// Let the endpoint reach an announced member without any discovery, e.g. on a local network
+fn add_address(peer: &Peer, addr: NodeAddr) {
+    if let Err(e) = peer
+        .endpoint()
+        .add_node_addr_with_source(addr, "phiny-room")
+    {
+        debug!("Failed to add the address of a room member: {}", e);
+    }
+}
+
+fn topic_id(room: &str) -> iroh_gossip::proto::TopicId {
+    let mut key = b"phiny-room:".to_vec();
+    key.extend_from_slice(room.as_bytes());
+    (*blake3::hash(&key).as_bytes()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    use iroh::RelayMode;
+
+    use super::*;
+    use crate::p2p::{DiscoveryMode, PeerConfig};
+
+    const ROOM: &str = "standup";
+
+    fn presence_of(peer: &Peer) -> Inner {
+        Inner {
+            room: ROOM.to_string(),
+            peer: peer.clone(),
+            members: StdMutex::new(HashMap::new()),
+            departed: StdMutex::new(HashMap::new()),
+            events: broadcast::channel(16).0,
+        }
+    }
+
+    async fn local_peer() -> Peer {
+        Peer::new(PeerConfig {
+            discovery: DiscoveryMode::None,
+            relay_mode: RelayMode::Disabled,
+            bind_addr_v4: Some(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)),
+            ..PeerConfig::default()
+        })
+        .await
+        .unwrap()
+    }
+
+    fn members(inner: &Inner) -> Vec<NodeId> {
+        inner.members.lock().unwrap().keys().copied().collect()
+    }
+
+    #[tokio::test]
+    async fn replayed_announcements_do_not_revive_a_member_which_left() {
+        let (alice, bob) = (local_peer().await, local_peer().await);
+        let (alice, bob_presence) = (presence_of(&alice), presence_of(&bob));
+        let ticket = bob
+            .sign_ticket(Ticket::new(bob.address()).with_call_id(ROOM))
+            .unwrap()
+            .encode()
+            .unwrap();
+        let announce = |state, sequence| bob_presence.sign(&ticket, state, sequence).unwrap();
+
+        let in_call = announce(PresenceState::InCall, 1);
+        alice.handle(&in_call).unwrap();
+        assert_eq!(members(&alice), [bob.node_id()]);
+
+        alice.handle(&announce(PresenceState::Left, 2)).unwrap();
+        assert!(members(&alice).is_empty());
+
+        // Gossip may deliver the older announcement again, it must not bring bob back
+        alice.handle(&in_call).unwrap();
+        alice.expire();
+        assert!(members(&alice).is_empty());
+
+        // A newer announcement means bob came back
+        alice.handle(&announce(PresenceState::InCall, 3)).unwrap();
+        assert_eq!(members(&alice), [bob.node_id()]);
+        assert!(alice.departed.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,416 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        Mutex as StdMutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{Notify, oneshot};
+
+use crate::p2p::channel::Channel;
+
+/// What [`Connection::send`](crate::p2p::Connection::send) does with a message of a channel
+/// whose previous ones are still waiting for the network
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Wait for room in the send queue, nothing is lost
+    #[default]
+    Block,
+    /// Drop the new message when the queue is full
+    DropNewest,
+    /// Drop the oldest queued message of the same channel to make room for the new one
+    DropOldest,
+    /// Keep at most one message of the channel queued, a new one takes the place of the
+    /// queued one
+    ReplaceLatest,
+}
+
+/// How the messages of a channel are queued for the reliable stream of a
+/// [`Connection`](crate::p2p::Connection)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SendPolicy {
+    pub overflow: OverflowPolicy,
+    /// Messages which waited longer than this in the queue are dropped rather than sent
+    pub max_age: Option<Duration>,
+}
+
+impl SendPolicy {
+    /// Never lose a message, the default for every channel
+    pub const fn reliable() -> Self {
+        SendPolicy {
+            overflow: OverflowPolicy::Block,
+            max_age: None,
+        }
+    }
+
+    /// For real-time media: never wait, and never queue more than `max_age` worth of messages
+    pub const fn realtime(max_age: Duration) -> Self {
+        SendPolicy {
+            overflow: OverflowPolicy::DropOldest,
+            max_age: Some(max_age),
+        }
+    }
+
+    /// For state updates where only the newest one matters
+    pub const fn latest() -> Self {
+        SendPolicy {
+            overflow: OverflowPolicy::ReplaceLatest,
+            max_age: None,
+        }
+    }
+}
+
+// Items processed in order by the sending loop
+pub(crate) enum Outgoing {
+    Frame(Channel, Vec<u8>),
+    // Finish the send stream once everything before it is written, then report back
+    Finish(oneshot::Sender<()>),
+}
+
+impl Outgoing {
+    fn channel(&self) -> Option<Channel> {
+        match self {
+            Outgoing::Frame(channel, _) => Some(*channel),
+            Outgoing::Finish(_) => None,
+        }
+    }
+}
+
+/// What became of a pushed message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Pushed {
+    /// Queued, possibly in place of older messages of its channel
+    Queued,
+    /// Dropped by the policy of its channel
+    Dropped,
+    /// The sending loop is gone
+    Closed,
+}
+
+struct Queued {
+    outgoing: Outgoing,
+    queued_at: Instant,
+}
+
+struct State {
+    items: VecDeque<Queued>,
+    policies: HashMap<Channel, SendPolicy>,
+    closed: bool,
+}
+
+/// Send queue of a connection, applying the [`SendPolicy`] of each channel
+pub(crate) struct SendQueue {
+    state: StdMutex<State>,
+    capacity: usize,
+    readable: Notify,
+    writable: Notify,
+    dropped: AtomicU64,
+}
+
+impl SendQueue {
+    pub(crate) fn new(capacity: usize, policies: HashMap<Channel, SendPolicy>) -> Self {
+        SendQueue {
+            state: StdMutex::new(State {
+                items: VecDeque::with_capacity(capacity),
+                policies,
+                closed: false,
+            }),
+            capacity: capacity.max(1),
+            readable: Notify::new(),
+            writable: Notify::new(),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn policy(&self, channel: Channel) -> SendPolicy {
+        let state = self.state.lock().unwrap();
+        state.policies.get(&channel).copied().unwrap_or_default()
+    }
+
+    pub(crate) fn set_policy(&self, channel: Channel, policy: SendPolicy) {
+        self.state.lock().unwrap().policies.insert(channel, policy);
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.state.lock().unwrap().items.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Messages dropped by the policies so far, replaced and expired ones included
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn record_drop(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Queue a message without waiting, hands the message back when its channel blocks and
+    /// the queue is full
+    pub(crate) fn try_push(&self, channel: Channel, data: Vec<u8>) -> Result<Pushed, Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Ok(Pushed::Closed);
+        }
+        let policy = state.policies.get(&channel).copied().unwrap_or_default();
+        let full = state.items.len() >= self.capacity;
+        let same_channel = |queued: &Queued| queued.outgoing.channel() == Some(channel);
+
+        match policy.overflow {
+            OverflowPolicy::ReplaceLatest => {
+                if let Some(queued) = state.items.iter_mut().rev().find(|q| same_channel(q)) {
+                    *queued = Queued::new(Outgoing::Frame(channel, data));
+                    self.record_drop();
+                    return Ok(Pushed::Queued);
+                }
+                if full {
+                    self.record_drop();
+                    return Ok(Pushed::Dropped);
+                }
+            }
+            OverflowPolicy::DropOldest if full => {
+                self.record_drop();
+                let Some(oldest) = state.items.iter().position(same_channel) else {
+                    return Ok(Pushed::Dropped);
+                };
+                state.items.remove(oldest);
+            }
+            OverflowPolicy::DropNewest if full => {
+                self.record_drop();
+                return Ok(Pushed::Dropped);
+            }
+            OverflowPolicy::Block if full => return Err(data),
+            _ => {}
+        }
+        state
+            .items
+            .push_back(Queued::new(Outgoing::Frame(channel, data)));
+        drop(state);
+        self.readable.notify_one();
+        Ok(Pushed::Queued)
+    }
+
+    /// Queue a message, waiting for room when its channel blocks
+    pub(crate) async fn push(&self, channel: Channel, data: Vec<u8>) -> Pushed {
+        let mut data = data;
+        loop {
+            let writable = self.writable.notified();
+            tokio::pin!(writable);
+            writable.as_mut().enable();
+            match self.try_push(channel, data) {
+                Ok(pushed) => return pushed,
+                Err(returned) => data = returned,
+            }
+            writable.await;
+        }
+    }
+
+    /// Queue the end of the stream behind everything already queued, regardless of the capacity
+    pub(crate) fn finish(&self, done: oneshot::Sender<()>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return false;
+        }
+        state.items.push_back(Queued::new(Outgoing::Finish(done)));
+        drop(state);
+        self.readable.notify_one();
+        true
+    }
+
+    /// The next message to write, skipping the ones which waited longer than their channel allows
+    pub(crate) async fn pop(&self) -> Outgoing {
+        loop {
+            let readable = self.readable.notified();
+            tokio::pin!(readable);
+            readable.as_mut().enable();
+            let mut expired = false;
+            {
+                let mut state = self.state.lock().unwrap();
+                while let Some(queued) = state.items.pop_front() {
+                    let max_age = queued
+                        .outgoing
+                        .channel()
+                        .and_then(|channel| state.policies.get(&channel))
+                        .and_then(|policy| policy.max_age);
+                    if max_age.is_some_and(|max_age| queued.queued_at.elapsed() > max_age) {
+                        self.record_drop();
+                        expired = true;
+                        continue;
+                    }
+                    drop(state);
+                    self.writable.notify_waiters();
+                    return queued.outgoing;
+                }
+            }
+            if expired {
+                self.writable.notify_waiters();
+            }
+            readable.await;
+        }
+    }
+
+    /// Refuse further messages and release the senders waiting for room
+    pub(crate) fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        state.items.clear();
+        drop(state);
+        self.writable.notify_waiters();
+    }
+}
+
+impl Queued {
+    fn new(outgoing: Outgoing) -> Self {
+        Queued {
+            outgoing,
+            queued_at: Instant::now(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    const MEDIA: Channel = Channel::application(0);
+    const STATE: Channel = Channel::application(1);
+
+    fn queue(capacity: usize, policies: &[(Channel, SendPolicy)]) -> SendQueue {
+        SendQueue::new(capacity, policies.iter().copied().collect())
+    }
+
+    fn policy(overflow: OverflowPolicy) -> SendPolicy {
+        SendPolicy {
+            overflow,
+            max_age: None,
+        }
+    }
+
+    async fn pop_frame(queue: &SendQueue) -> (Channel, Vec<u8>) {
+        match queue.pop().await {
+            Outgoing::Frame(channel, data) => (channel, data),
+            Outgoing::Finish(_) => panic!("expected a frame"),
+        }
+    }
+
+    #[test]
+    fn block_hands_the_message_back_when_full() {
+        let queue = queue(1, &[]);
+        assert_eq!(queue.try_push(MEDIA, vec![1]), Ok(Pushed::Queued));
+        assert_eq!(queue.try_push(MEDIA, vec![2]), Err(vec![2]));
+        assert_eq!(queue.dropped(), 0);
+    }
+
+    #[tokio::test]
+    async fn drop_newest_keeps_the_queued_messages() {
+        let queue = queue(1, &[(MEDIA, policy(OverflowPolicy::DropNewest))]);
+        assert_eq!(queue.try_push(MEDIA, vec![1]), Ok(Pushed::Queued));
+        assert_eq!(queue.try_push(MEDIA, vec![2]), Ok(Pushed::Dropped));
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(pop_frame(&queue).await, (MEDIA, vec![1]));
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_makes_room_in_its_own_channel() {
+        let queue = queue(2, &[(MEDIA, policy(OverflowPolicy::DropOldest))]);
+        queue.try_push(MEDIA, vec![1]).unwrap();
+        queue.try_push(STATE, vec![10]).unwrap();
+        assert_eq!(queue.try_push(MEDIA, vec![2]), Ok(Pushed::Queued));
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(pop_frame(&queue).await, (STATE, vec![10]));
+        assert_eq!(pop_frame(&queue).await, (MEDIA, vec![2]));
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_without_a_message_of_its_channel_drops_the_new_one() {
+        let queue = queue(1, &[(MEDIA, policy(OverflowPolicy::DropOldest))]);
+        queue.try_push(STATE, vec![10]).unwrap();
+        assert_eq!(queue.try_push(MEDIA, vec![1]), Ok(Pushed::Dropped));
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(pop_frame(&queue).await, (STATE, vec![10]));
+    }
+
+    #[tokio::test]
+    async fn replace_latest_keeps_a_single_message() {
+        let queue = queue(2, &[(STATE, SendPolicy::latest())]);
+        queue.try_push(STATE, vec![1]).unwrap();
+        queue.try_push(MEDIA, vec![10]).unwrap();
+        assert_eq!(queue.try_push(STATE, vec![2]), Ok(Pushed::Queued));
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.dropped(), 1);
+        // The replacement keeps the place of the replaced message
+        assert_eq!(pop_frame(&queue).await, (STATE, vec![2]));
+
+        // Full with nothing of its channel to replace
+        queue.try_push(MEDIA, vec![11]).unwrap();
+        assert_eq!(queue.try_push(STATE, vec![3]), Ok(Pushed::Dropped));
+        assert_eq!(queue.dropped(), 2);
+    }
+
+    #[tokio::test]
+    async fn pop_skips_expired_messages() {
+        let queue = queue(
+            4,
+            &[(MEDIA, SendPolicy::realtime(Duration::from_millis(10)))],
+        );
+        queue.try_push(MEDIA, vec![1]).unwrap();
+        queue.try_push(STATE, vec![10]).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        queue.try_push(MEDIA, vec![2]).unwrap();
+
+        assert_eq!(pop_frame(&queue).await, (STATE, vec![10]));
+        assert_eq!(pop_frame(&queue).await, (MEDIA, vec![2]));
+        assert_eq!(queue.dropped(), 1);
+    }
+
+    #[tokio::test]
+    async fn finish_ignores_the_capacity() {
+        let queue = queue(1, &[]);
+        queue.try_push(MEDIA, vec![1]).unwrap();
+        let (done, _) = oneshot::channel();
+        assert!(queue.finish(done));
+        assert_eq!(queue.len(), 2);
+
+        assert_eq!(pop_frame(&queue).await, (MEDIA, vec![1]));
+        assert!(matches!(queue.pop().await, Outgoing::Finish(_)));
+    }
+
+    #[tokio::test]
+    async fn close_releases_blocked_senders() {
+        let queue = Arc::new(queue(1, &[]));
+        queue.try_push(MEDIA, vec![1]).unwrap();
+        let blocked = tokio::spawn({
+            let queue = Arc::clone(&queue);
+            async move { queue.push(MEDIA, vec![2]).await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!blocked.is_finished());
+
+        queue.close();
+        assert_eq!(blocked.await.unwrap(), Pushed::Closed);
+        assert!(queue.is_empty());
+        let (done, _) = oneshot::channel();
+        assert!(!queue.finish(done));
+    }
+
+    #[tokio::test]
+    async fn pop_makes_room_for_blocked_senders() {
+        let queue = Arc::new(queue(1, &[]));
+        queue.try_push(MEDIA, vec![1]).unwrap();
+        let blocked = tokio::spawn({
+            let queue = Arc::clone(&queue);
+            async move { queue.push(MEDIA, vec![2]).await }
+        });
+
+        assert_eq!(pop_frame(&queue).await, (MEDIA, vec![1]));
+        assert_eq!(blocked.await.unwrap(), Pushed::Queued);
+        assert_eq!(pop_frame(&queue).await, (MEDIA, vec![2]));
+    }
+}
@@ -0,0 +1,943 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
+
+use anyhow::{Context as _, Result, anyhow, bail};
+use bincode::{Decode, Encode};
+use iroh::{NodeAddr, NodeId};
+use log::debug;
+use tokio::{
+    select,
+    sync::{broadcast, mpsc, watch},
+    task::JoinHandle,
+};
+
+use crate::p2p::{
+    channel::{Channel, MessageReceiver},
+    close::CloseCode,
+    connection::{Connection, Message},
+    peer::{ConnectionListener, Peer},
+    presence::{Presence, PresenceEvent, PresenceMember, PresenceState},
+    ticket::Ticket,
+};
+
+// How long a member announced by someone else has to connect to us before we dial it ourselves
+const MESH_GRACE: Duration = Duration::from_secs(2);
+
+/// How the members of a room are connected
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RoomTopology {
+    /// Every member is connected to every other one and sends them its own frames
+    #[default]
+    Mesh,
+    /// Members are only connected to the creator of the room, which forwards their frames to
+    /// everyone else without decoding them; each member uploads a single copy of its audio
+    Forwarded,
+}
+
+#[derive(Debug, Clone)]
+pub struct RoomConfig {
+    /// Participants of the room, ourselves included; joiners beyond are refused. The
+    /// [`PeerConfig::max_connections`](crate::p2p::PeerConfig::max_connections) of the peer must
+    /// leave room for the other members to connect
+    pub max_members: usize,
+    /// Time allowed for a joiner to introduce itself, and for a member to welcome us
+    pub join_timeout: Duration,
+    /// Only used by the creator, joiners follow the topology of the room they join
+    pub topology: RoomTopology,
+    /// Frames sent with [`Room::send_audio`] while not speaking are not sent, nor forwarded
+    pub drop_silent: bool,
+}
+
+impl Default for RoomConfig {
+    fn default() -> Self {
+        RoomConfig {
+            // Every other member connecting to us fits the default `max_connections`
+            max_members: 5,
+            join_timeout: Duration::from_secs(10),
+            topology: RoomTopology::Mesh,
+            drop_silent: false,
+        }
+    }
+}
+
+/// Membership messages exchanged by the members of a room
+#[derive(Debug, Clone, Encode, Decode)]
+enum RoomMessage {
+    /// First message of a connection: the room the dialer is in and its signed ticket
+    Join { room: String, ticket: String },
+    /// Answer to a join: the tickets of the other members, for the joiner to connect to unless
+    /// the sender forwards their frames
+    Welcome {
+        members: Vec<String>,
+        forwarded: bool,
+    },
+    /// Answer to a join which was not accepted
+    Refused { reason: String },
+    /// A member joined through the sender
+    MemberJoined { ticket: String },
+    /// A member reached through the sender, which forwards its frames, left
+    MemberLeft { node_id: [u8; 32], reason: String },
+    /// The sender is leaving the room
+    Leave,
+}
+
+impl Message for RoomMessage {
+    const CHANNEL: Channel = Channel::ROOM;
+
+    fn serialize(&self) -> Result<Vec<u8>> {
+        Ok(bincode::encode_to_vec(self, bincode::config::standard())?)
+    }
+
+    fn deserialize(data: &[u8]) -> Result<Self> {
+        Ok(bincode::decode_from_slice(data, bincode::config::standard())?.0)
+    }
+}
+
+/// A datagram forwarded through the creator of a [`RoomTopology::Forwarded`] room
+#[derive(Debug, Clone, Encode, Decode)]
+struct Relayed {
+    /// The member which sent the frame, set by the forwarder
+    from: [u8; 32],
+    channel: u16,
+    voice_active: bool,
+    payload: Vec<u8>,
+}
+
+impl Message for Relayed {
+    const CHANNEL: Channel = Channel::RELAYED;
+
+    fn serialize(&self) -> Result<Vec<u8>> {
+        Ok(bincode::encode_to_vec(self, bincode::config::standard())?)
+    }
+
+    fn deserialize(data: &[u8]) -> Result<Self> {
+        Ok(bincode::decode_from_slice(data, bincode::config::standard())?.0)
+    }
+}
+
+/// Another participant of a [`Room`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoomMember {
+    pub node_id: NodeId,
+    pub display_name: Option<String>,
+}
+
+/// Membership changes of a [`Room`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RoomEvent {
+    Joined(RoomMember),
+    Left { node_id: NodeId, reason: String },
+}
+
+/// Receives the messages of a single type from every member of a [`Room`]
+pub struct RoomReceiver<M> {
+    receiver: mpsc::Receiver<(NodeId, M)>,
+}
+
+impl<M> RoomReceiver<M> {
+    /// Receive the next message and the member which sent it
+    pub async fn recv(&mut self) -> Option<(NodeId, M)> {
+        self.receiver.recv().await
+    }
+}
+
+// Holds the place of a joiner until it became a member, or gave up
+struct Joining<'a> {
+    joining: &'a StdMutex<HashSet<NodeId>>,
+    node_id: NodeId,
+}
+
+impl Drop for Joining<'_> {
+    fn drop(&mut self) {
+        self.joining.lock().unwrap().remove(&self.node_id);
+    }
+}
+
+struct Member {
+    connection: Arc<Connection>,
+    /// Whether we opened the connection
+    dialed: bool,
+    ticket: String,
+}
+
+// Starts forwarding the messages of a new member, false once its receiver is gone
+type Subscriber = Box<dyn Fn(NodeId, &Arc<Connection>) -> bool + Send + Sync>;
+// Delivers a relayed payload to the receiver of its channel, false once the receiver is gone
+type RelaySink = Box<dyn Fn(NodeId, &[u8]) -> bool + Send + Sync>;
+
+struct Inner {
+    id: String,
+    peer: Peer,
+    ticket: Ticket,
+    encoded_ticket: String,
+    config: RoomConfig,
+    /// We created a forwarded room and relay the frames of its members
+    forwarding: bool,
+    // Locked after `subscribers` when both are needed
+    members: StdMutex<HashMap<NodeId, Member>>,
+    /// Joiners being welcomed, their place is taken before they become members
+    joining: StdMutex<HashSet<NodeId>>,
+    subscribers: StdMutex<Vec<Subscriber>>,
+    relay_sinks: StdMutex<HashMap<Channel, RelaySink>>,
+    /// The member forwarding our frames, when we joined a forwarded room
+    forwarder: StdMutex<Option<NodeId>>,
+    /// Members we are not connected to, whose frames come through the forwarder
+    relayed: StdMutex<HashMap<NodeId, RoomMember>>,
+    events: broadcast::Sender<RoomEvent>,
+    closing: watch::Sender<bool>,
+}
+
+/// A call between several peers, every member connected to every other one
+///
+/// A joiner connects to any member with its ticket, gets the tickets of the other members and
+/// connects to them as well, while the member it joined through announces it to the others.
+/// Meant for small groups: every member sends its audio to every other member. With
+/// [`RoomTopology::Forwarded`] the creator of the room relays the frames of everyone instead,
+/// so members with a slow uplink only send one copy.
+///
+/// When the peer runs [`PeerConfig::gossip`](crate::p2p::PeerConfig::gossip), members also
+/// announce themselves on a gossip topic derived from the room id, see [`Presence`], and
+/// connect to the members found there, e.g. ones which joined through someone else at the
+/// same time.
+///
+/// Membership travels on a channel reserved by phiny, applications exchange their own
+/// messages with [`Room::send`], [`Room::send_unreliable`] and the room receivers.
+pub struct Room {
+    inner: Arc<Inner>,
+    accept_task: JoinHandle<()>,
+    presence: Option<Presence>,
+}
+
+impl Room {
+    /// Open a new room, joiners are accepted from `listener`
+    pub async fn create(
+        peer: &Peer,
+        listener: ConnectionListener,
+        id: impl Into<String>,
+        config: RoomConfig,
+    ) -> Result<Self> {
+        let forwarding = config.topology == RoomTopology::Forwarded;
+        let mut room = Self::start(peer, listener, id.into(), config, forwarding).await?;
+        room.start_presence(Vec::new()).await?;
+        Ok(room)
+    }
+
+    /// Join the room of a ticket obtained from [`Room::ticket`]
+    pub async fn join(
+        peer: &Peer,
+        listener: ConnectionListener,
+        ticket: &Ticket,
+        config: RoomConfig,
+    ) -> Result<Self> {
+        let id = ticket
+            .call_id
+            .clone()
+            .ok_or_else(|| anyhow!("Ticket does not point to a room"))?;
+        let mut room = Self::start(peer, listener, id, config, false).await?;
+
+        let members = room.inner.connect(ticket).await?;
+        for member in members {
+            let Ok(ticket) = Ticket::decode(&member) else {
+                debug!("Ignoring invalid member ticket");
+                continue;
+            };
+            if room.inner.is_member_or_self(ticket.node_addrs.node_id) {
+                continue;
+            }
+            if let Err(e) = room.inner.connect(&ticket).await {
+                debug!(
+                    "Failed to connect to member {}: {}",
+                    ticket.node_addrs.node_id, e
+                );
+            }
+        }
+        room.start_presence(vec![ticket.node_addrs.clone()]).await?;
+        Ok(room)
+    }
+
+    async fn start(
+        peer: &Peer,
+        mut listener: ConnectionListener,
+        id: String,
+        config: RoomConfig,
+        forwarding: bool,
+    ) -> Result<Self> {
+        // Every other member may connect to us, e.g. the joiners of a room we created
+        let max_connections = peer.config().max_connections;
+        if max_connections + 1 < config.max_members {
+            bail!(
+                "A room of {} members needs a peer accepting {} connections, it accepts {}",
+                config.max_members,
+                config.max_members - 1,
+                max_connections
+            );
+        }
+
+        let mut ticket = Ticket::new(peer.reachable_address().await).with_call_id(id.clone());
+        if let Some(name) = &peer.config().display_name {
+            ticket = ticket.with_display_name(name);
+        }
+        let ticket = peer.sign_ticket(ticket)?;
+        let (events, _) = broadcast::channel(16);
+
+        let inner = Arc::new(Inner {
+            id,
+            peer: peer.clone(),
+            encoded_ticket: ticket.encode()?,
+            ticket,
+            config,
+            forwarding,
+            members: StdMutex::new(HashMap::new()),
+            joining: StdMutex::new(HashSet::new()),
+            subscribers: StdMutex::new(Vec::new()),
+            relay_sinks: StdMutex::new(HashMap::new()),
+            forwarder: StdMutex::new(None),
+            relayed: StdMutex::new(HashMap::new()),
+            events,
+            closing: watch::Sender::new(false),
+        });
+
+        let accept_inner = Arc::clone(&inner);
+        let accept_task = tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok(Some(connection)) => {
+                        tokio::spawn(Arc::clone(&accept_inner).welcome(connection));
+                    }
+                    Ok(None) => break,
+                    Err(e) => debug!("Failed to accept a room connection: {}", e),
+                }
+            }
+        });
+
+        Ok(Room {
+            inner,
+            accept_task,
+            presence: None,
+        })
+    }
+
+    // Announce ourselves on the gossip topic of the room and connect to the members found there
+    async fn start_presence(&mut self, bootstrap: Vec<NodeAddr>) -> Result<()> {
+        if self.inner.peer.gossip().is_none() {
+            return Ok(());
+        }
+        let presence = Presence::join(
+            &self.inner.peer,
+            &self.inner.ticket,
+            PresenceState::InCall,
+            bootstrap,
+        )
+        .await?;
+        let events = presence.events();
+        tokio::spawn(Arc::clone(&self.inner).discover(events, presence.members()));
+        self.presence = Some(presence);
+        Ok(())
+    }
+
+    pub fn id(&self) -> &str {
+        &self.inner.id
+    }
+
+    /// Our signed ticket in the room, as announced to the other members
+    ///
+    /// Share [`Room::invite_ticket`] with peers which should join, it differs in forwarded rooms.
+    pub fn ticket(&self) -> &Ticket {
+        &self.inner.ticket
+    }
+
+    /// Ticket to share with peers which should join the room: ours, or the one of the
+    /// forwarder when we joined a forwarded room, as only its creator lets joiners in
+    pub fn invite_ticket(&self) -> Ticket {
+        let forwarder = *self.inner.forwarder.lock().unwrap();
+        forwarder
+            .and_then(|node_id| {
+                let members = self.inner.members.lock().unwrap();
+                Ticket::decode(&members.get(&node_id)?.ticket).ok()
+            })
+            .unwrap_or_else(|| self.inner.ticket.clone())
+    }
+
+    /// The other participants of the room, including the ones reached through a forwarder
+    pub fn members(&self) -> Vec<RoomMember> {
+        let mut members: Vec<_> = self
+            .inner
+            .members
+            .lock()
+            .unwrap()
+            .values()
+            .map(|member| member_of(&member.connection))
+            .collect();
+        members.extend(self.inner.relayed.lock().unwrap().values().cloned());
+        members
+    }
+
+    /// Whether this peer forwards the frames of the other members
+    pub fn is_forwarder(&self) -> bool {
+        self.inner.is_forwarder()
+    }
+
+    /// The connection to a member, e.g. for its stats or verification code
+    pub fn connection(&self, node_id: &NodeId) -> Option<Arc<Connection>> {
+        self.inner
+            .members
+            .lock()
+            .unwrap()
+            .get(node_id)
+            .map(|member| Arc::clone(&member.connection))
+    }
+
+    /// The members announced on the gossip topic of the room, `None` without gossip
+    pub fn presence(&self) -> Option<&Presence> {
+        self.presence.as_ref()
+    }
+
+    /// Subscribe to members joining and leaving
+    pub fn events(&self) -> broadcast::Receiver<RoomEvent> {
+        self.inner.events.subscribe()
+    }
+
+    /// Send a message to every member over the reliable stream
+    ///
+    /// Members whose connection failed are skipped, they leave the room shortly after. Reliable
+    /// messages are not relayed, so in a forwarded room only the forwarder reaches everyone;
+    /// fails for the other members.
+    pub async fn send<M: Message + Clone>(&self, message: M) -> Result<()> {
+        if self.inner.forwarder.lock().unwrap().is_some() {
+            bail!("Members of a forwarded room only reach the forwarder, messages are not relayed");
+        }
+        for connection in self.inner.connections() {
+            if let Err(e) = connection.send(message.clone()).await {
+                debug!("Failed to send to {}: {}", connection.remote_node_id(), e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Send a message to every member as datagrams, see [`Connection::send_unreliable`]
+    ///
+    /// In a forwarded room, members send it to the forwarder which relays it to the others.
+    pub fn send_unreliable<M: Message + Clone>(&self, message: M) -> Result<()> {
+        self.send_audio(message, true)
+    }
+
+    /// Like [`Room::send_unreliable`], flagging whether the frame holds speech so silent
+    /// frames are dropped when [`RoomConfig::drop_silent`] is set
+    pub fn send_audio<M: Message + Clone>(&self, message: M, voice_active: bool) -> Result<()> {
+        if !voice_active && self.inner.config.drop_silent {
+            return Ok(());
+        }
+        let forwarder = *self.inner.forwarder.lock().unwrap();
+        match forwarder.and_then(|forwarder| self.connection(&forwarder)) {
+            Some(forwarder) => forwarder.send_unreliable(Relayed {
+                from: *self.inner.peer.node_id().as_bytes(),
+                channel: M::CHANNEL.id(),
+                voice_active,
+                payload: message.serialize()?,
+            }),
+            None => {
+                for connection in self.inner.connections() {
+                    if let Err(e) = connection.send_unreliable(message.clone()) {
+                        debug!("Failed to send to {}: {}", connection.remote_node_id(), e);
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Receive the messages of type `M` sent by any member with [`Room::send`]; in a forwarded
+    /// room only the forwarder sends them
+    ///
+    /// Like [`Connection::receiver`], there should be a single receiver per message type.
+    pub fn receiver<M: Message>(&self) -> RoomReceiver<M> {
+        let (sender, messages) = mpsc::channel(self.inner.peer.config().buffer_size);
+        self.inner
+            .subscribe(sender, |connection| connection.receiver::<M>());
+        RoomReceiver { receiver: messages }
+    }
+
+    /// Receive the messages of type `M` sent by any member with [`Room::send_unreliable`] or
+    /// [`Room::send_audio`], forwarded ones included
+    pub fn unreliable_receiver<M: Message>(&self) -> RoomReceiver<M> {
+        let (sender, messages) = mpsc::channel(self.inner.peer.config().buffer_size);
+        let relay_sender = sender.clone();
+        let sink: RelaySink = Box::new(move |from, payload| {
+            match M::deserialize(payload) {
+                // Late frames are worthless, drop them when the receiver lags behind
+                Ok(message) => {
+                    if let Err(mpsc::error::TrySendError::Closed(_)) =
+                        relay_sender.try_send((from, message))
+                    {
+                        return false;
+                    }
+                }
+                Err(e) => debug!("Dropping malformed relayed message from {}: {}", from, e),
+            }
+            true
+        });
+        self.inner
+            .relay_sinks
+            .lock()
+            .unwrap()
+            .insert(M::CHANNEL, sink);
+        self.inner
+            .subscribe(sender, |connection| connection.unreliable_receiver::<M>());
+        RoomReceiver { receiver: messages }
+    }
+
+    /// Tell every member we are leaving and close the connections
+    pub async fn leave(mut self) {
+        if let Some(presence) = self.presence.take() {
+            presence.leave().await;
+        }
+        self.accept_task.abort();
+        self.inner.closing.send_replace(true);
+
+        let members: Vec<_> = self.inner.members.lock().unwrap().drain().collect();
+        for (_, member) in members {
+            let _ = member.connection.send(RoomMessage::Leave).await;
+            let _ = member
+                .connection
+                .close(CloseCode::Normal, "left the room")
+                .await;
+        }
+    }
+}
+
+impl Drop for Room {
+    fn drop(&mut self) {
+        self.accept_task.abort();
+        self.inner.closing.send_replace(true);
+    }
+}
+
+impl Inner {
+    fn connections(&self) -> Vec<Arc<Connection>> {
+        self.members
+            .lock()
+            .unwrap()
+            .values()
+            .map(|member| Arc::clone(&member.connection))
+            .collect()
+    }
+
+    fn is_forwarder(&self) -> bool {
+        self.forwarding
+    }
+
+    fn is_member_or_self(&self, node_id: NodeId) -> bool {
+        node_id == self.peer.node_id() || self.members.lock().unwrap().contains_key(&node_id)
+    }
+
+    fn subscribe<M: Message>(
+        &self,
+        sender: mpsc::Sender<(NodeId, M)>,
+        receiver: impl Fn(&Connection) -> MessageReceiver<M> + Send + Sync + 'static,
+    ) {
+        let subscriber: Subscriber = Box::new(move |node_id, connection| {
+            if sender.is_closed() {
+                return false;
+            }
+            let receiver = receiver(connection);
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                loop {
+                    match receiver.recv().await {
+                        Ok(Some(message)) => {
+                            if sender.send((node_id, message)).await.is_err() {
+                                break;
+                            }
+                        }
+                        Ok(None) => break,
+                        Err(e) => debug!("Dropping malformed message from {}: {}", node_id, e),
+                    }
+                }
+            });
+            true
+        });
+
+        let mut subscribers = self.subscribers.lock().unwrap();
+        for (node_id, member) in self.members.lock().unwrap().iter() {
+            subscriber(*node_id, &member.connection);
+        }
+        subscribers.push(subscriber);
+    }
+
+    // Connect to a member and introduce ourselves, returns the tickets of the other members
+    async fn connect(self: &Arc<Self>, ticket: &Ticket) -> Result<Vec<String>> {
+        let connection = Arc::new(self.peer.connect_ticket(ticket).await?);
+        connection
+            .send(RoomMessage::Join {
+                room: self.id.clone(),
+                ticket: self.encoded_ticket.clone(),
+            })
+            .await?;
+
+        let reply = tokio::time::timeout(
+            self.config.join_timeout,
+            connection.receive::<RoomMessage>(),
+        )
+        .await
+        .context("Timed out waiting to be welcomed in the room")
+        .flatten();
+        match reply {
+            Ok(Some(RoomMessage::Welcome { members, forwarded })) => {
+                let node_id = connection.remote_node_id();
+                self.add_member(connection, ticket.encode()?, true);
+                if !forwarded {
+                    return Ok(members);
+                }
+                // Everyone else is reached through the member we joined
+                *self.forwarder.lock().unwrap() = Some(node_id);
+                for member in members {
+                    self.add_relayed(&member);
+                }
+                Ok(Vec::new())
+            }
+            Ok(Some(RoomMessage::Refused { reason })) => {
+                let _ = connection.close(CloseCode::Normal, "refused").await;
+                bail!("Refused to join the room: {}", reason)
+            }
+            _ => {
+                let _ = connection
+                    .close(CloseCode::ProtocolError, "expected a welcome")
+                    .await;
+                bail!("Member did not welcome us in the room")
+            }
+        }
+    }
+
+    // Handle a peer connecting to us, answering its join
+    async fn welcome(self: Arc<Self>, connection: Connection) {
+        let node_id = connection.remote_node_id();
+        let join = tokio::time::timeout(
+            self.config.join_timeout,
+            connection.receive::<RoomMessage>(),
+        )
+        .await;
+        let Ok(Ok(Some(RoomMessage::Join { room, ticket }))) = join else {
+            debug!("Closing connection from {}, it did not join", node_id);
+            let _ = connection
+                .close(CloseCode::ProtocolError, "expected to join a room")
+                .await;
+            return;
+        };
+
+        let refusal = {
+            let members = self.members.lock().unwrap();
+            let mut joining = self.joining.lock().unwrap();
+            if room != self.id {
+                Some("no such room")
+            } else if !Ticket::decode(&ticket)
+                .is_ok_and(|ticket| ticket.node_addrs.node_id == node_id)
+            {
+                Some("invalid ticket")
+            } else if joining.contains(&node_id) {
+                Some("already joining")
+            } else if !members.contains_key(&node_id)
+                && members.len() + joining.len() + 1 >= self.config.max_members
+            {
+                Some("room is full")
+            } else if self.forwarder.lock().unwrap().is_some() {
+                Some("members of a forwarded room only connect to its creator")
+            } else {
+                joining.insert(node_id);
+                None
+            }
+        };
+        if let Some(reason) = refusal {
+            debug!("Refusing {} in the room: {}", node_id, reason);
+            let _ = connection
+                .send(RoomMessage::Refused {
+                    reason: reason.to_string(),
+                })
+                .await;
+            let _ = connection.close(CloseCode::Normal, reason).await;
+            return;
+        }
+        let _joining = Joining {
+            joining: &self.joining,
+            node_id,
+        };
+
+        let others: Vec<_> = self
+            .members
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(id, _)| **id != node_id)
+            .map(|(_, member)| (member.ticket.clone(), Arc::clone(&member.connection)))
+            .collect();
+        let welcome = RoomMessage::Welcome {
+            members: others.iter().map(|(ticket, _)| ticket.clone()).collect(),
+            forwarded: self.is_forwarder(),
+        };
+        if let Err(e) = connection.send(welcome).await {
+            debug!("Failed to welcome {}: {}", node_id, e);
+            return;
+        }
+        for (_, other) in &others {
+            let _ = other
+                .send(RoomMessage::MemberJoined {
+                    ticket: ticket.clone(),
+                })
+                .await;
+        }
+        self.add_member(Arc::new(connection), ticket, false);
+    }
+
+    fn add_member(self: &Arc<Self>, connection: Arc<Connection>, ticket: String, dialed: bool) {
+        let node_id = connection.remote_node_id();
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let mut members = self.members.lock().unwrap();
+
+        // Both sides dialed each other: keep the connection opened by the smaller node id, so
+        // both ends settle on the same one
+        let keep_existing = members.get(&node_id).is_some_and(|existing| {
+            existing.dialed != dialed && existing.dialed == (self.peer.node_id() < node_id)
+        });
+        if keep_existing {
+            drop(members);
+            tokio::spawn(async move {
+                let _ = connection
+                    .close(CloseCode::Normal, "duplicate connection")
+                    .await;
+            });
+            return;
+        }
+
+        let replaced = members.insert(
+            node_id,
+            Member {
+                connection: Arc::clone(&connection),
+                dialed,
+                ticket,
+            },
+        );
+        drop(members);
+        subscribers.retain(|subscriber| subscriber(node_id, &connection));
+        drop(subscribers);
+
+        match replaced {
+            Some(old) => {
+                tokio::spawn(async move {
+                    let _ = old
+                        .connection
+                        .close(CloseCode::Normal, "duplicate connection")
+                        .await;
+                });
+            }
+            None => {
+                let _ = self.events.send(RoomEvent::Joined(member_of(&connection)));
+            }
+        }
+        tokio::spawn(Arc::clone(self).relay(Arc::clone(&connection)));
+        tokio::spawn(Arc::clone(self).follow(connection));
+    }
+
+    fn add_relayed(&self, ticket: &str) {
+        let Ok(ticket) = Ticket::decode(ticket) else {
+            return;
+        };
+        let member = RoomMember {
+            node_id: ticket.node_addrs.node_id,
+            display_name: ticket.display_name,
+        };
+        if member.node_id == self.peer.node_id() {
+            return;
+        }
+        let joined = self
+            .relayed
+            .lock()
+            .unwrap()
+            .insert(member.node_id, member.clone())
+            .is_none();
+        if joined {
+            let _ = self.events.send(RoomEvent::Joined(member));
+        }
+    }
+
+    fn remove_relayed(&self, node_id: NodeId, reason: String) {
+        if self.relayed.lock().unwrap().remove(&node_id).is_some() {
+            let _ = self.events.send(RoomEvent::Left { node_id, reason });
+        }
+    }
+
+    // Handle the frames relayed by (or to be forwarded from) a member
+    async fn relay(self: Arc<Self>, connection: Arc<Connection>) {
+        let node_id = connection.remote_node_id();
+        let frames = connection.unreliable_receiver::<Relayed>();
+        let mut closing = self.closing.subscribe();
+        loop {
+            let frame = select! {
+                frame = frames.recv() => frame,
+                _ = closing.wait_for(|closing| *closing) => return,
+            };
+            let mut frame = match frame {
+                Ok(Some(frame)) => frame,
+                Ok(None) => return,
+                Err(e) => {
+                    debug!("Dropping malformed relayed frame from {}: {}", node_id, e);
+                    continue;
+                }
+            };
+
+            let from = if self.is_forwarder() {
+                if !frame.voice_active && self.config.drop_silent {
+                    continue;
+                }
+                // Never trust the sender about who it is
+                frame.from = *node_id.as_bytes();
+                for other in self.connections() {
+                    if other.remote_node_id() != node_id {
+                        let _ = other.send_unreliable(frame.clone());
+                    }
+                }
+                node_id
+            } else if *self.forwarder.lock().unwrap() == Some(node_id) {
+                match NodeId::from_bytes(&frame.from) {
+                    Ok(from) => from,
+                    Err(_) => continue,
+                }
+            } else {
+                debug!(
+                    "Dropping relayed frame from {}, it is no forwarder",
+                    node_id
+                );
+                continue;
+            };
+
+            let mut sinks = self.relay_sinks.lock().unwrap();
+            let channel = Channel::from_id(frame.channel);
+            if let Some(sink) = sinks.get(&channel)
+                && !sink(from, &frame.payload)
+            {
+                sinks.remove(&channel);
+            }
+        }
+    }
+
+    // Handle the membership messages of a member until it leaves
+    async fn follow(self: Arc<Self>, connection: Arc<Connection>) {
+        let node_id = connection.remote_node_id();
+        let mut closing = self.closing.subscribe();
+        let reason = loop {
+            let message = select! {
+                message = connection.receive::<RoomMessage>() => message,
+                _ = closing.wait_for(|closing| *closing) => return,
+            };
+            match message {
+                Ok(Some(RoomMessage::MemberJoined { ticket })) => {
+                    if *self.forwarder.lock().unwrap() == Some(node_id) {
+                        self.add_relayed(&ticket);
+                    } else if let Ok(ticket) = Ticket::decode(&ticket) {
+                        tokio::spawn(Arc::clone(&self).expect(ticket));
+                    }
+                }
+                Ok(Some(RoomMessage::MemberLeft {
+                    node_id: left,
+                    reason,
+                })) => {
+                    if *self.forwarder.lock().unwrap() == Some(node_id)
+                        && let Ok(left) = NodeId::from_bytes(&left)
+                    {
+                        self.remove_relayed(left, reason);
+                    }
+                }
+                Ok(Some(RoomMessage::Leave)) => break "left the room".to_string(),
+                Ok(Some(message)) => debug!("Ignoring unexpected room message {:?}", message),
+                Ok(None) => break connection.status().to_string(),
+                Err(e) => debug!("Ignoring malformed room message: {}", e),
+            }
+        };
+
+        let removed = {
+            let mut members = self.members.lock().unwrap();
+            let current = members
+                .get(&node_id)
+                .is_some_and(|member| Arc::ptr_eq(&member.connection, &connection));
+            current && members.remove(&node_id).is_some()
+        };
+        if removed {
+            let _ = self.events.send(RoomEvent::Left {
+                node_id,
+                reason: reason.clone(),
+            });
+            if self.is_forwarder() {
+                for other in self.connections() {
+                    let _ = other
+                        .send(RoomMessage::MemberLeft {
+                            node_id: *node_id.as_bytes(),
+                            reason: reason.clone(),
+                        })
+                        .await;
+                }
+            } else if *self.forwarder.lock().unwrap() == Some(node_id) {
+                // Everyone else was reached through the forwarder
+                let relayed: Vec<_> = self.relayed.lock().unwrap().keys().copied().collect();
+                for relayed in relayed {
+                    self.remove_relayed(relayed, "the forwarder left".to_string());
+                }
+            }
+        }
+        let _ = connection.close(CloseCode::Normal, "left the room").await;
+    }
+
+    // A member announced a joiner, which normally connects to us; dial it if it does not
+    async fn expect(self: Arc<Self>, ticket: Ticket) {
+        tokio::time::sleep(MESH_GRACE).await;
+        if *self.closing.borrow() || self.is_member_or_self(ticket.node_addrs.node_id) {
+            return;
+        }
+        if let Err(e) = self.connect(&ticket).await {
+            debug!(
+                "Failed to connect to member {}: {}",
+                ticket.node_addrs.node_id, e
+            );
+        }
+    }
+
+    // Dial the members announced on the gossip topic which we are not connected to
+    async fn discover(
+        self: Arc<Self>,
+        mut events: broadcast::Receiver<PresenceEvent>,
+        known: Vec<PresenceMember>,
+    ) {
+        for member in known {
+            self.discovered(member);
+        }
+        let mut closing = self.closing.subscribe();
+        loop {
+            let event = select! {
+                event = events.recv() => event,
+                _ = closing.wait_for(|closing| *closing) => return,
+            };
+            match event {
+                Ok(PresenceEvent::Joined(member)) => self.discovered(member),
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    }
+
+    fn discovered(self: &Arc<Self>, member: PresenceMember) {
+        // In a forwarded room everyone only connects to the creator
+        if self.is_forwarder() || self.forwarder.lock().unwrap().is_some() {
+            return;
+        }
+        if member.state != PresenceState::Left && !self.is_member_or_self(member.node_id) {
+            tokio::spawn(Arc::clone(self).expect(member.ticket));
+        }
+    }
+}
+
+fn member_of(connection: &Connection) -> RoomMember {
+    RoomMember {
+        node_id: connection.remote_node_id(),
+        display_name: connection.remote_display_name().map(str::to_string),
+    }
+}
@@ -0,0 +1,189 @@
+use anyhow::{Result, anyhow};
+use iroh::{NodeId, endpoint};
+
+// Label of the TLS exporter, see RFC 5705
+const EXPORTER_LABEL: &[u8] = b"EXPORTER-phiny-sas";
+const EMOJI_COUNT: usize = 7;
+
+// Same table as the Matrix SAS verification, so the pictures are easy to tell apart
+const EMOJI: [(&str, &str); 64] = [
+    ("🐶", "dog"),
+    ("🐱", "cat"),
+    ("🦁", "lion"),
+    ("🐎", "horse"),
+    ("🦄", "unicorn"),
+    ("🐷", "pig"),
+    ("🐘", "elephant"),
+    ("🐰", "rabbit"),
+    ("🐼", "panda"),
+    ("🐓", "rooster"),
+    ("🐧", "penguin"),
+    ("🐢", "turtle"),
+    ("🐟", "fish"),
+    ("🐙", "octopus"),
+    ("🦋", "butterfly"),
+    ("🌷", "flower"),
+    ("🌳", "tree"),
+    ("🌵", "cactus"),
+    ("🍄", "mushroom"),
+    ("🌏", "globe"),
+    ("🌙", "moon"),
+    ("☁️", "cloud"),
+    ("🔥", "fire"),
+    ("🍌", "banana"),
+    ("🍎", "apple"),
+    ("🍓", "strawberry"),
+    ("🌽", "corn"),
+    ("🍕", "pizza"),
+    ("🎂", "cake"),
+    ("❤️", "heart"),
+    ("😀", "smiley"),
+    ("🤖", "robot"),
+    ("🎩", "hat"),
+    ("👓", "glasses"),
+    ("🔧", "spanner"),
+    ("🎅", "santa"),
+    ("👍", "thumbs up"),
+    ("☂️", "umbrella"),
+    ("⌛", "hourglass"),
+    ("⏰", "clock"),
+    ("🎁", "gift"),
+    ("💡", "light bulb"),
+    ("📕", "book"),
+    ("✏️", "pencil"),
+    ("📎", "paperclip"),
+    ("✂️", "scissors"),
+    ("🔒", "lock"),
+    ("🔑", "key"),
+    ("🔨", "hammer"),
+    ("☎️", "telephone"),
+    ("🏁", "flag"),
+    ("🚂", "train"),
+    ("🚲", "bicycle"),
+    ("✈️", "aeroplane"),
+    ("🚀", "rocket"),
+    ("🏆", "trophy"),
+    ("⚽", "ball"),
+    ("🎸", "guitar"),
+    ("🎺", "trumpet"),
+    ("🔔", "bell"),
+    ("⚓", "anchor"),
+    ("🎧", "headphones"),
+    ("📁", "folder"),
+    ("📌", "pin"),
+];
+
+/// Short code both ends of a connection derive, equal only if nobody sits in the middle
+///
+/// It is derived from the TLS session secrets, which depend on the whole QUIC handshake
+/// transcript, and from both node ids. Reading it to each other over the call proves the
+/// connection reached the intended peer, see [`Connection::short_auth_string`](crate::p2p::Connection::short_auth_string).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShortAuthString([u8; 6]);
+
+impl ShortAuthString {
+    pub(crate) fn derive(
+        connection: &endpoint::Connection,
+        local: NodeId,
+        remote: NodeId,
+    ) -> Result<Self> {
+        // Both sides must feed the node ids in the same order
+        let (first, second) = if local.as_bytes() < remote.as_bytes() {
+            (local, remote)
+        } else {
+            (remote, local)
+        };
+        let mut context = Vec::with_capacity(64);
+        context.extend_from_slice(first.as_bytes());
+        context.extend_from_slice(second.as_bytes());
+
+        let mut secret = [0u8; 32];
+        connection
+            .export_keying_material(&mut secret, EXPORTER_LABEL, &context)
+            .map_err(|_| anyhow!("Failed to export keying material"))?;
+
+        let mut bytes = [0u8; 6];
+        bytes.copy_from_slice(&blake3::hash(&secret).as_bytes()[..6]);
+        Ok(ShortAuthString(bytes))
+    }
+
+    // 42 bits, taken 6 at a time
+    fn emoji_indices(&self) -> [usize; EMOJI_COUNT] {
+        let mut bits = [0u8; 8];
+        bits[2..].copy_from_slice(&self.0);
+        let bits = u64::from_be_bytes(bits) >> 6;
+        std::array::from_fn(|i| ((bits >> (6 * (EMOJI_COUNT - 1 - i))) & 0x3f) as usize)
+    }
+
+    /// Seven emoji
+    pub fn emoji(&self) -> Vec<&'static str> {
+        self.emoji_indices().iter().map(|&i| EMOJI[i].0).collect()
+    }
+
+    /// The names of the emoji, to read out loud
+    pub fn words(&self) -> Vec<&'static str> {
+        self.emoji_indices().iter().map(|&i| EMOJI[i].1).collect()
+    }
+
+    /// Three numbers between 1000 and 9191, for those who prefer digits
+    pub fn decimal(&self) -> [u16; 3] {
+        let b = self.0.map(u16::from);
+        [
+            ((b[0] << 5) | (b[1] >> 3)) + 1000,
+            (((b[1] & 0x7) << 10) | (b[2] << 2) | (b[3] >> 6)) + 1000,
+            (((b[3] & 0x3f) << 7) | (b[4] >> 1)) + 1000,
+        ]
+    }
+}
+
+impl std::fmt::Display for ShortAuthString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let [a, b, c] = self.decimal();
+        write!(f, "{} ({}-{}-{})", self.words().join(" "), a, b, c)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATTERN: ShortAuthString = ShortAuthString([0x01, 0x23, 0x45, 0x67, 0x89, 0xab]);
+
+    #[test]
+    fn emoji_take_the_first_42_bits() {
+        assert_eq!(PATTERN.emoji_indices(), [0, 18, 13, 5, 25, 56, 38]);
+        assert_eq!(
+            PATTERN.words(),
+            [
+                "dog",
+                "mushroom",
+                "octopus",
+                "pig",
+                "strawberry",
+                "ball",
+                "hourglass"
+            ]
+        );
+        assert_eq!(PATTERN.emoji()[0], "🐶");
+    }
+
+    #[test]
+    fn decimal_takes_13_bits_per_number() {
+        assert_eq!(PATTERN.decimal(), [1036, 4349, 6060]);
+        assert_eq!(
+            PATTERN.to_string(),
+            "dog mushroom octopus pig strawberry ball hourglass (1036-4349-6060)"
+        );
+    }
+
+    #[test]
+    fn values_stay_in_range() {
+        for byte in [0x00, 0x55, 0xaa, 0xff] {
+            let sas = ShortAuthString([byte; 6]);
+            assert!(sas.emoji_indices().iter().all(|&i| i < EMOJI.len()));
+            assert!(sas.decimal().iter().all(|n| (1000..=9191).contains(n)));
+        }
+        assert_eq!(ShortAuthString([0xff; 6]).decimal(), [9191; 3]);
+        assert_eq!(ShortAuthString([0; 6]).decimal(), [1000; 3]);
+    }
+}
@@ -0,0 +1,479 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use anyhow::{Result, anyhow};
+use bincode::{Decode, Encode};
+use data_encoding::HEXLOWER;
+use iroh::{NodeAddr, NodeId};
+use log::debug;
+use tokio::{
+    select,
+    sync::{broadcast, mpsc, oneshot, watch},
+    task::JoinHandle,
+    time::Instant,
+};
+
+use crate::p2p::{
+    close::CloseCode,
+    connection::{Connection, Message},
+    peer::{ConnectionListener, Peer},
+    status::ConnectionStatus,
+    ticket::Ticket,
+};
+
+/// Identifies a session across the connections it is carried on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Encode, Decode)]
+pub struct SessionId([u8; 16]);
+
+impl SessionId {
+    /// A new random session id
+    pub fn new() -> Self {
+        SessionId(rand::random())
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+}
+
+impl Default for SessionId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Display for SessionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", HEXLOWER.encode(&self.0))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    /// Delay before the first reconnection attempt, doubled after every failed attempt
+    pub reconnect_initial_delay: Duration,
+    /// Upper bound of the delay between reconnection attempts
+    pub reconnect_max_delay: Duration,
+    /// How long a lost connection may take to be resumed before the session ends
+    pub resume_timeout: Duration,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            reconnect_initial_delay: Duration::from_millis(250),
+            reconnect_max_delay: Duration::from_secs(5),
+            resume_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Things happening to a session's connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionEvent {
+    /// The connection was lost, the session tries to resume on a new one
+    Disconnected { reason: String },
+    /// The connecting side is about to make a reconnection attempt
+    Reconnecting { attempt: u32, delay: Duration },
+    /// The session continues on a new connection; audio pipelines should reset their jitter
+    /// buffers and decoder state, sequence numbers simply continue
+    Resumed,
+    /// The session ended for good
+    Closed { reason: String },
+}
+
+enum Role {
+    // We dialed, so we are the one reconnecting
+    Outgoing {
+        peer: Box<Peer>,
+        ticket: Box<Ticket>,
+    },
+    // We were dialed, resumed connections are handed over by the session listener
+    Incoming {
+        resumes: mpsc::Receiver<Connection>,
+    },
+}
+
+/// A resilient session with a remote peer on top of [`Connection`]
+///
+/// When the connection is lost because of a transport failure (e.g. a Wi-Fi switch), the
+/// connecting side reconnects to the same [`NodeAddr`] with exponential backoff and both sides
+/// continue on the new connection under the same [`SessionId`]. A connection closed on purpose
+/// by either side ends the session.
+///
+/// [`Session::receive`] follows the session across reconnections, while sending fails for as
+/// long as the session is reconnecting.
+pub struct Session {
+    id: SessionId,
+    connection: Arc<watch::Sender<Arc<Connection>>>,
+    ended: Arc<watch::Sender<Option<String>>>,
+    events: broadcast::Sender<SessionEvent>,
+    _drop_signal: oneshot::Sender<()>,
+}
+
+impl Session {
+    /// Open a new session to the peer at `addr`
+    pub async fn connect(peer: &Peer, addr: NodeAddr, config: SessionConfig) -> Result<Self> {
+        Self::connect_ticket(peer, Ticket::new(addr), config).await
+    }
+
+    /// Open a new session to the peer a ticket points to, presenting the ticket if it is
+    /// signed, on every reconnection as well
+    pub async fn connect_ticket(
+        peer: &Peer,
+        ticket: Ticket,
+        config: SessionConfig,
+    ) -> Result<Self> {
+        let id = SessionId::new();
+        let connection = peer.connect_with_session(&ticket, Some(id)).await?;
+        Ok(Self::spawn(
+            id,
+            connection,
+            Role::Outgoing {
+                peer: Box::new(peer.clone()),
+                ticket: Box::new(ticket),
+            },
+            config,
+        ))
+    }
+
+    fn spawn(id: SessionId, connection: Connection, role: Role, config: SessionConfig) -> Self {
+        let connection = Arc::new(watch::Sender::new(Arc::new(connection)));
+        let ended = Arc::new(watch::Sender::new(None));
+        let (events, _) = broadcast::channel(16);
+        let (drop_tx, drop_rx) = oneshot::channel();
+
+        tokio::spawn(supervise(
+            id,
+            role,
+            config,
+            Arc::clone(&connection),
+            Arc::clone(&ended),
+            events.clone(),
+            drop_rx,
+        ));
+
+        Self {
+            id,
+            connection,
+            ended,
+            events,
+            _drop_signal: drop_tx,
+        }
+    }
+
+    pub fn id(&self) -> SessionId {
+        self.id
+    }
+
+    /// The connection currently carrying the session
+    pub fn connection(&self) -> Arc<Connection> {
+        self.connection.borrow().clone()
+    }
+
+    /// Watch the connection carrying the session, it changes every time the session resumes
+    pub fn watch_connection(&self) -> watch::Receiver<Arc<Connection>> {
+        self.connection.subscribe()
+    }
+
+    /// Subscribe to the events of the session
+    pub fn events(&self) -> broadcast::Receiver<SessionEvent> {
+        self.events.subscribe()
+    }
+
+    /// Whether the session ended for good
+    pub fn is_closed(&self) -> bool {
+        self.ended.borrow().is_some()
+    }
+
+    /// Wait until the session ends and return why
+    pub async fn closed(&self) -> String {
+        let mut ended = self.ended.subscribe();
+        match ended.wait_for(Option::is_some).await {
+            Ok(reason) => reason.clone().unwrap_or_default(),
+            Err(_) => "session dropped".to_string(),
+        }
+    }
+
+    /// Send a message on the current connection, fails while the session is reconnecting
+    pub async fn send<M: Message>(&self, message: M) -> Result<()> {
+        self.connection().send(message).await
+    }
+
+    /// Send a datagram on the current connection, fails while the session is reconnecting
+    pub fn send_unreliable<M: Message>(&self, message: M) -> Result<()> {
+        self.connection().send_unreliable(message)
+    }
+
+    /// Receive a message, waiting across reconnections; `None` once the session ended
+    pub async fn receive<M: Message>(&self) -> Result<Option<M>> {
+        let mut connection = self.connection.subscribe();
+        loop {
+            let current = connection.borrow_and_update().clone();
+            if let Some(message) = current.receive::<M>().await? {
+                return Ok(Some(message));
+            }
+            if !self.wait_for_resume(&mut connection).await {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Receive a datagram, waiting across reconnections; `None` once the session ended
+    pub async fn receive_unreliable<M: Message>(&self) -> Result<Option<M>> {
+        let mut connection = self.connection.subscribe();
+        loop {
+            let current = connection.borrow_and_update().clone();
+            if let Some(message) = current.receive_unreliable::<M>().await? {
+                return Ok(Some(message));
+            }
+            if !self.wait_for_resume(&mut connection).await {
+                return Ok(None);
+            }
+        }
+    }
+
+    // Wait for the session to move to a new connection, false when the session ended instead
+    async fn wait_for_resume(&self, connection: &mut watch::Receiver<Arc<Connection>>) -> bool {
+        let mut ended = self.ended.subscribe();
+        select! {
+            changed = connection.changed() => changed.is_ok(),
+            _ = ended.wait_for(Option::is_some) => false,
+        }
+    }
+
+    /// End the session and gracefully close its connection
+    pub async fn close(&self, code: CloseCode, reason: &str) -> Result<()> {
+        end(&self.ended, &self.events, reason.to_string());
+        self.connection().close(code, reason).await
+    }
+}
+
+// Only transport failures are worth resuming, a connection closed on purpose ends the session
+fn is_resumable(status: &ConnectionStatus) -> bool {
+    matches!(
+        status,
+        ConnectionStatus::Error(_)
+            | ConnectionStatus::Closed {
+                code: CloseCode::Timeout,
+                ..
+            }
+    )
+}
+
+fn end(
+    ended: &watch::Sender<Option<String>>,
+    events: &broadcast::Sender<SessionEvent>,
+    reason: String,
+) {
+    let first = ended.send_if_modified(|ended| {
+        if ended.is_some() {
+            return false;
+        }
+        *ended = Some(reason.clone());
+        true
+    });
+    if first {
+        let _ = events.send(SessionEvent::Closed { reason });
+    }
+}
+
+// Watches the connection of a session and moves the session to a new one when it is lost
+async fn supervise(
+    id: SessionId,
+    mut role: Role,
+    config: SessionConfig,
+    connection: Arc<watch::Sender<Arc<Connection>>>,
+    ended: Arc<watch::Sender<Option<String>>>,
+    events: broadcast::Sender<SessionEvent>,
+    mut drop_rx: oneshot::Receiver<()>,
+) {
+    loop {
+        let current = connection.borrow().clone();
+        let status = select! {
+            status = current.closed() => status,
+            // The remote may notice the loss first and come back before our connection
+            // times out, the new connection then supersedes the old one
+            Some(resumed) = next_resume(&mut role) => {
+                tokio::spawn(async move {
+                    let _ = current
+                        .close(CloseCode::GoingAway, "session resumed on a new connection")
+                        .await;
+                });
+                connection.send_replace(Arc::new(resumed));
+                let _ = events.send(SessionEvent::Resumed);
+                continue;
+            },
+            _ = &mut drop_rx => return,
+        };
+
+        if ended.borrow().is_some() || !is_resumable(&status) {
+            end(&ended, &events, status.to_string());
+            return;
+        }
+
+        debug!("Session {} lost its connection : {}", id, status);
+        let _ = events.send(SessionEvent::Disconnected {
+            reason: status.to_string(),
+        });
+
+        let resumed = select! {
+            resumed = resume(id, &mut role, &config, &events) => resumed,
+            _ = &mut drop_rx => return,
+        };
+        match resumed {
+            Some(resumed) => {
+                connection.send_replace(Arc::new(resumed));
+                let _ = events.send(SessionEvent::Resumed);
+            }
+            None => {
+                end(
+                    &ended,
+                    &events,
+                    format!("could not resume after {}", status),
+                );
+                return;
+            }
+        }
+    }
+}
+
+async fn next_resume(role: &mut Role) -> Option<Connection> {
+    match role {
+        Role::Incoming { resumes } => resumes.recv().await,
+        Role::Outgoing { .. } => std::future::pending().await,
+    }
+}
+
+// Get a new connection for the session within the resume timeout
+async fn resume(
+    id: SessionId,
+    role: &mut Role,
+    config: &SessionConfig,
+    events: &broadcast::Sender<SessionEvent>,
+) -> Option<Connection> {
+    let deadline = Instant::now() + config.resume_timeout;
+    match role {
+        Role::Incoming { resumes } => tokio::time::timeout_at(deadline, resumes.recv())
+            .await
+            .ok()
+            .flatten(),
+        Role::Outgoing { peer, ticket } => {
+            let mut delay = config.reconnect_initial_delay;
+            let mut attempt = 1;
+            while Instant::now() + delay < deadline {
+                let _ = events.send(SessionEvent::Reconnecting { attempt, delay });
+                tokio::time::sleep(delay).await;
+
+                let connect = peer.connect_with_session(ticket, Some(id));
+                match tokio::time::timeout_at(deadline, connect).await {
+                    Ok(Ok(connection)) => return Some(connection),
+                    Ok(Err(e)) => debug!("Reconnection attempt {} failed : {:#}", attempt, e),
+                    Err(_) => break,
+                }
+
+                delay = (delay * 2).min(config.reconnect_max_delay);
+                attempt += 1;
+            }
+            None
+        }
+    }
+}
+
+/// Accepts sessions on top of a [`ConnectionListener`]
+///
+/// Connections resuming a known session are handed to that session instead of being returned
+/// from [`SessionListener::accept`]. Only the node which opened a session may resume it.
+pub struct SessionListener {
+    sessions: mpsc::Receiver<Result<Session>>,
+    close_signal: oneshot::Sender<()>,
+    accept_task: JoinHandle<()>,
+}
+
+impl SessionListener {
+    pub fn new(mut listener: ConnectionListener, config: SessionConfig) -> Self {
+        let (sessions_tx, sessions) = mpsc::channel(1);
+        let (close_signal, mut close_rx) = oneshot::channel::<()>();
+
+        let accept_task = tokio::spawn(async move {
+            let mut known: HashMap<SessionId, (NodeId, mpsc::Sender<Connection>)> = HashMap::new();
+
+            loop {
+                let accepted = select! {
+                    accepted = listener.accept() => accepted,
+                    _ = &mut close_rx => break,
+                };
+                let connection = match accepted {
+                    Ok(Some(connection)) => connection,
+                    Ok(None) => break,
+                    Err(e) => {
+                        if sessions_tx.send(Err(e)).await.is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                };
+
+                // Forget the sessions which ended
+                known.retain(|_, (_, resumes)| !resumes.is_closed());
+
+                let session_id = connection.session_id();
+                if let Some((node_id, resumes)) = session_id.and_then(|id| known.get(&id)) {
+                    if *node_id != connection.remote_node_id() {
+                        let _ = connection
+                            .close(
+                                CloseCode::HandshakeFailed,
+                                "session belongs to another node",
+                            )
+                            .await;
+                    } else if let Err(mpsc::error::TrySendError::Full(connection)) =
+                        resumes.try_send(connection)
+                    {
+                        let _ = connection
+                            .close(CloseCode::GoingAway, "session is already resuming")
+                            .await;
+                    }
+                    continue;
+                }
+
+                let (resumes_tx, resumes) = mpsc::channel(1);
+                if let Some(id) = session_id {
+                    known.insert(id, (connection.remote_node_id(), resumes_tx));
+                }
+                let session = Session::spawn(
+                    session_id.unwrap_or_default(),
+                    connection,
+                    Role::Incoming { resumes },
+                    config.clone(),
+                );
+                if sessions_tx.send(Ok(session)).await.is_err() {
+                    break;
+                }
+            }
+
+            listener.close().await;
+        });
+
+        Self {
+            sessions,
+            close_signal,
+            accept_task,
+        }
+    }
+
+    /// Accept the next new session
+    pub async fn accept(&mut self) -> Result<Option<Session>> {
+        match self.sessions.recv().await {
+            Some(result) => result.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Stop accepting sessions and close the underlying listener
+    pub async fn close(self) {
+        let _ = self.close_signal.send(());
+        self.accept_task
+            .await
+            .map_err(|e| anyhow!("Session listener task failed: {}", e))
+            .unwrap_or_else(|e| debug!("{}", e));
+    }
+}
@@ -0,0 +1,460 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{Context as _, Result, anyhow};
+use bincode::{Decode, Encode};
+use log::{debug, warn};
+use tokio::{
+    select,
+    sync::{oneshot, watch},
+    time::Instant,
+};
+
+use crate::p2p::{
+    channel::Channel,
+    connection::{Connection, Message},
+    session::Session,
+    transport::Transport,
+};
+
+/// Messages exchanged by the two sides of a call
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub enum SignalMessage {
+    /// The caller asks the callee to start a call
+    Invite,
+    /// The callee is alerting the user about the call
+    Ringing,
+    /// The callee took the call
+    Accept,
+    /// The callee declined the call
+    Reject { reason: String },
+    /// The callee is already in another call
+    Busy,
+    /// The caller gave up before the call was accepted
+    Cancel,
+    /// Either side ended an established call
+    Hangup,
+}
+
+impl Message for SignalMessage {
+    const CHANNEL: Channel = Channel::SIGNALING;
+
+    fn serialize(&self) -> Result<Vec<u8>> {
+        Ok(bincode::encode_to_vec(self, bincode::config::standard())?)
+    }
+
+    fn deserialize(data: &[u8]) -> Result<Self> {
+        Ok(bincode::decode_from_slice(data, bincode::config::standard())?.0)
+    }
+}
+
+/// Which side of the call we are
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallDirection {
+    Outgoing,
+    Incoming,
+}
+
+/// Why a call ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CallEnd {
+    Rejected(String),
+    Busy,
+    Cancelled,
+    HungUp { by_remote: bool },
+    TimedOut,
+    ConnectionLost,
+}
+
+impl std::fmt::Display for CallEnd {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CallEnd::Rejected(reason) => write!(f, "call rejected: {}", reason),
+            CallEnd::Busy => write!(f, "peer is busy"),
+            CallEnd::Cancelled => write!(f, "call cancelled"),
+            CallEnd::HungUp { by_remote: true } => write!(f, "peer hung up"),
+            CallEnd::HungUp { by_remote: false } => write!(f, "hung up"),
+            CallEnd::TimedOut => write!(f, "call timed out"),
+            CallEnd::ConnectionLost => write!(f, "connection lost"),
+        }
+    }
+}
+
+/// State of a call as seen by one side
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CallState {
+    /// Invite sent, waiting for the callee to ring
+    Dialing,
+    /// The callee is ringing and has not answered yet
+    Ringing,
+    /// Both sides agreed on the call
+    Active,
+    Ended(CallEnd),
+}
+
+#[derive(Debug, Clone)]
+pub struct CallConfig {
+    /// How long to wait for an invite to be answered with ringing (or to arrive at all)
+    pub invite_timeout: Duration,
+    /// How long a call may ring before it is given up
+    pub ring_timeout: Duration,
+}
+
+impl Default for CallConfig {
+    fn default() -> Self {
+        CallConfig {
+            invite_timeout: Duration::from_secs(5),
+            ring_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Signaling for a one-to-one call on top of a [`Connection`] or a [`Session`]
+///
+/// A background task follows the signaling messages of the remote side and enforces the
+/// timeouts of [`CallConfig`]. Dropping the call hangs up (or cancels) it.
+pub struct Call {
+    transport: Transport,
+    direction: CallDirection,
+    state: Arc<watch::Sender<CallState>>,
+    _drop_signal: oneshot::Sender<()>,
+}
+
+impl Call {
+    /// Invite the peer to a call, use [`Call::established`] to wait for the answer
+    pub async fn dial(connection: Arc<Connection>, config: CallConfig) -> Result<Self> {
+        Self::dial_on(Transport::Connection(connection), config).await
+    }
+
+    /// Like [`Call::dial`], the call survives the session resuming on a new connection
+    pub async fn dial_session(session: Arc<Session>, config: CallConfig) -> Result<Self> {
+        Self::dial_on(Transport::Session(session), config).await
+    }
+
+    /// Wait for the peer's invite and start ringing, the call is then accepted or rejected
+    pub async fn answer(connection: Arc<Connection>, config: CallConfig) -> Result<Self> {
+        Self::answer_on(Transport::Connection(connection), config).await
+    }
+
+    /// Like [`Call::answer`], the call survives the session resuming on a new connection
+    pub async fn answer_session(session: Arc<Session>, config: CallConfig) -> Result<Self> {
+        Self::answer_on(Transport::Session(session), config).await
+    }
+
+    async fn dial_on(transport: Transport, config: CallConfig) -> Result<Self> {
+        transport
+            .send(SignalMessage::Invite)
+            .await
+            .context("Failed to send call invite")?;
+        Ok(Self::spawn(
+            transport,
+            CallDirection::Outgoing,
+            CallState::Dialing,
+            config,
+        ))
+    }
+
+    async fn answer_on(transport: Transport, config: CallConfig) -> Result<Self> {
+        let invite = tokio::time::timeout(config.invite_timeout, transport.receive())
+            .await
+            .context("Timed out waiting for a call invite")??;
+
+        match invite {
+            Some(SignalMessage::Invite) => {}
+            Some(other) => return Err(anyhow!("Expected a call invite, got {:?}", other)),
+            None => return Err(anyhow!("Connection closed before a call invite arrived")),
+        }
+
+        transport
+            .send(SignalMessage::Ringing)
+            .await
+            .context("Failed to send ringing")?;
+        Ok(Self::spawn(
+            transport,
+            CallDirection::Incoming,
+            CallState::Ringing,
+            config,
+        ))
+    }
+
+    fn spawn(
+        transport: Transport,
+        direction: CallDirection,
+        initial: CallState,
+        config: CallConfig,
+    ) -> Self {
+        let (state, _) = watch::channel(initial);
+        let state = Arc::new(state);
+        let (drop_tx, drop_rx) = oneshot::channel();
+
+        tokio::spawn(run(
+            transport.clone(),
+            direction,
+            Arc::clone(&state),
+            config,
+            drop_rx,
+        ));
+
+        Self {
+            transport,
+            direction,
+            state,
+            _drop_signal: drop_tx,
+        }
+    }
+
+    /// The connection the call is currently signaled on
+    pub fn connection(&self) -> Arc<Connection> {
+        self.transport.connection()
+    }
+
+    pub fn direction(&self) -> CallDirection {
+        self.direction
+    }
+
+    /// The current state of the call
+    pub fn state(&self) -> CallState {
+        self.state.borrow().clone()
+    }
+
+    /// Watch the state of the call as it changes
+    pub fn state_changes(&self) -> watch::Receiver<CallState> {
+        self.state.subscribe()
+    }
+
+    /// Wait until the call is active, fails if the call ended before that
+    pub async fn established(&self) -> Result<()> {
+        let mut state = self.state.subscribe();
+        let state = state
+            .wait_for(|state| matches!(state, CallState::Active | CallState::Ended(_)))
+            .await?;
+        match &*state {
+            CallState::Ended(end) => Err(anyhow!("Call was not established: {}", end)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Wait until the call ends and return why it ended
+    pub async fn ended(&self) -> CallEnd {
+        let mut state = self.state.subscribe();
+        match state
+            .wait_for(|state| matches!(state, CallState::Ended(_)))
+            .await
+            .as_deref()
+        {
+            Ok(CallState::Ended(end)) => end.clone(),
+            _ => CallEnd::ConnectionLost,
+        }
+    }
+
+    /// Take an incoming call which is ringing
+    pub async fn accept(&self) -> Result<()> {
+        self.transition(
+            "accept",
+            |state| self.direction == CallDirection::Incoming && *state == CallState::Ringing,
+            CallState::Active,
+        )?;
+        self.transport.send(SignalMessage::Accept).await
+    }
+
+    /// Decline an incoming call which is ringing
+    pub async fn reject(&self, reason: &str) -> Result<()> {
+        self.transition(
+            "reject",
+            |state| self.direction == CallDirection::Incoming && *state == CallState::Ringing,
+            CallState::Ended(CallEnd::Rejected(reason.to_string())),
+        )?;
+        self.transport
+            .send(SignalMessage::Reject {
+                reason: reason.to_string(),
+            })
+            .await
+    }
+
+    /// Decline an incoming call because we are already in another call
+    pub async fn busy(&self) -> Result<()> {
+        self.transition(
+            "report busy for",
+            |state| self.direction == CallDirection::Incoming && *state == CallState::Ringing,
+            CallState::Ended(CallEnd::Busy),
+        )?;
+        self.transport.send(SignalMessage::Busy).await
+    }
+
+    /// Give up an outgoing call which was not accepted yet
+    pub async fn cancel(&self) -> Result<()> {
+        self.transition(
+            "cancel",
+            |state| {
+                self.direction == CallDirection::Outgoing
+                    && matches!(state, CallState::Dialing | CallState::Ringing)
+            },
+            CallState::Ended(CallEnd::Cancelled),
+        )?;
+        self.transport.send(SignalMessage::Cancel).await
+    }
+
+    /// End an established call
+    pub async fn hangup(&self) -> Result<()> {
+        self.transition(
+            "hang up",
+            |state| *state == CallState::Active,
+            CallState::Ended(CallEnd::HungUp { by_remote: false }),
+        )?;
+        self.transport.send(SignalMessage::Hangup).await
+    }
+
+    fn transition(
+        &self,
+        action: &str,
+        allowed: impl FnOnce(&CallState) -> bool,
+        next: CallState,
+    ) -> Result<()> {
+        let mut current = None;
+        self.state.send_if_modified(|state| {
+            if allowed(state) {
+                *state = next;
+                true
+            } else {
+                current = Some(state.clone());
+                false
+            }
+        });
+        match current {
+            Some(state) => Err(anyhow!("Cannot {} the call while {:?}", action, state)),
+            None => Ok(()),
+        }
+    }
+}
+
+// Follows the remote side of the call until it ends
+async fn run(
+    transport: Transport,
+    direction: CallDirection,
+    state: Arc<watch::Sender<CallState>>,
+    config: CallConfig,
+    mut drop_rx: oneshot::Receiver<()>,
+) {
+    let mut state_rx = state.subscribe();
+    let mut entered = Instant::now();
+
+    loop {
+        let current = state_rx.borrow_and_update().clone();
+        let timeout = match current {
+            CallState::Dialing => Some(config.invite_timeout),
+            CallState::Ringing => Some(config.ring_timeout),
+            CallState::Active => None,
+            CallState::Ended(_) => break,
+        };
+        let expired = async {
+            match timeout {
+                Some(timeout) => tokio::time::sleep_until(entered + timeout).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        select! {
+            message = transport.receive() => match message {
+                Ok(Some(message)) => {
+                    if let Some(reply) = apply_remote(&state, direction, message)
+                        && let Err(e) = transport.send(reply).await
+                    {
+                        warn!("Failed to reply to call signal : {}", e);
+                    }
+                }
+                Ok(None) => {
+                    state.send_modify(|state| {
+                        if !matches!(state, CallState::Ended(_)) {
+                            *state = CallState::Ended(CallEnd::ConnectionLost);
+                        }
+                    });
+                }
+                Err(e) => warn!("Ignoring malformed call signal : {}", e),
+            },
+            _ = state_rx.changed() => {
+                entered = Instant::now();
+            },
+            _ = expired => {
+                let timed_out = state.send_if_modified(|state| {
+                    if *state == current {
+                        *state = CallState::Ended(CallEnd::TimedOut);
+                        true
+                    } else {
+                        false
+                    }
+                });
+                if timed_out {
+                    let message = match direction {
+                        CallDirection::Outgoing => SignalMessage::Cancel,
+                        CallDirection::Incoming => SignalMessage::Reject {
+                            reason: "no answer".to_string(),
+                        },
+                    };
+                    let _ = transport.send(message).await;
+                }
+            },
+            _ = &mut drop_rx => {
+                // The call handle was dropped, let the remote know we are gone
+                let message = match current {
+                    CallState::Active => SignalMessage::Hangup,
+                    _ if direction == CallDirection::Outgoing => SignalMessage::Cancel,
+                    _ => SignalMessage::Reject {
+                        reason: "call dismissed".to_string(),
+                    },
+                };
+                let _ = transport.send(message).await;
+                break;
+            }
+        }
+    }
+}
+
+// Applies a signal received from the remote, returns a message to reply with if any
+fn apply_remote(
+    state: &watch::Sender<CallState>,
+    direction: CallDirection,
+    message: SignalMessage,
+) -> Option<SignalMessage> {
+    let mut reply = None;
+    state.send_if_modified(|state| {
+        let next = match (direction, &*state, message) {
+            (CallDirection::Outgoing, CallState::Dialing, SignalMessage::Ringing) => {
+                CallState::Ringing
+            }
+            (
+                CallDirection::Outgoing,
+                CallState::Dialing | CallState::Ringing,
+                SignalMessage::Accept,
+            ) => CallState::Active,
+            (
+                CallDirection::Outgoing,
+                CallState::Dialing | CallState::Ringing,
+                SignalMessage::Reject { reason },
+            ) => CallState::Ended(CallEnd::Rejected(reason)),
+            (
+                CallDirection::Outgoing,
+                CallState::Dialing | CallState::Ringing,
+                SignalMessage::Busy,
+            ) => CallState::Ended(CallEnd::Busy),
+            (
+                CallDirection::Incoming,
+                CallState::Ringing | CallState::Active,
+                SignalMessage::Cancel,
+            ) => CallState::Ended(CallEnd::Cancelled),
+            (_, CallState::Active, SignalMessage::Hangup) => {
+                CallState::Ended(CallEnd::HungUp { by_remote: true })
+            }
+            (_, _, SignalMessage::Invite) => {
+                // Only one call per connection
+                reply = Some(SignalMessage::Busy);
+                return false;
+            }
+            (_, state, message) => {
+                debug!("Ignoring call signal {:?} while {:?}", message, state);
+                return false;
+            }
+        };
+        *state = next;
+        true
+    });
+    reply
+}
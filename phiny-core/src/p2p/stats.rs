@@ -0,0 +1,155 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        Mutex as StdMutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use iroh::endpoint::ConnectionType;
+
+use crate::p2p::keepalive::RttEstimate;
+
+// Path changes kept per connection, older ones are forgotten
+const MAX_PATH_CHANGES: usize = 64;
+
+/// The connection switched to another network path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathChange {
+    pub at: Instant,
+    pub connection_type: ConnectionType,
+}
+
+/// Snapshot of the traffic and network path of a [`Connection`](crate::p2p::Connection)
+#[derive(Debug, Clone)]
+pub struct ConnectionStats {
+    /// Messages written to the reliable stream, and their payload size
+    pub messages_sent: u64,
+    pub bytes_sent: u64,
+    /// Messages read from the reliable stream, and their payload size
+    pub messages_received: u64,
+    pub bytes_received: u64,
+    /// Datagrams handed to QUIC, and their size
+    pub datagrams_sent: u64,
+    pub datagram_bytes_sent: u64,
+    /// Datagrams received from the peer, and their size
+    pub datagrams_received: u64,
+    pub datagram_bytes_received: u64,
+    /// Received datagrams dropped because the application did not keep up, or did not receive
+    /// their channel
+    pub dropped_messages: u64,
+    /// Messages waiting to be written to the reliable stream
+    pub send_queue_depth: usize,
+    /// Messages dropped before reaching the reliable stream, by the
+    /// [`SendPolicy`](crate::p2p::SendPolicy) of their channel
+    pub dropped_sends: u64,
+    /// Round trip time estimated by QUIC
+    pub rtt: Duration,
+    /// Round trip time measured with heartbeats, including the send queue
+    pub heartbeat_rtt: Option<RttEstimate>,
+    /// Congestion window of QUIC, in bytes
+    pub congestion_window: u64,
+    /// Packets sent and lost by QUIC, and how often it backed off because of congestion
+    pub sent_packets: u64,
+    pub lost_packets: u64,
+    pub congestion_events: u64,
+    /// How the peer is currently reached
+    pub connection_type: ConnectionType,
+    /// Every path the connection went through, oldest first
+    pub path_changes: Vec<PathChange>,
+}
+
+impl ConnectionStats {
+    /// Share of the QUIC packets which were lost, between 0 and 1
+    pub fn loss_rate(&self) -> f64 {
+        if self.sent_packets == 0 {
+            return 0.0;
+        }
+        self.lost_packets as f64 / self.sent_packets as f64
+    }
+}
+
+/// Counters updated by the background tasks of a connection
+#[derive(Default)]
+pub(crate) struct Counters {
+    pub(crate) messages_sent: AtomicU64,
+    pub(crate) bytes_sent: AtomicU64,
+    pub(crate) messages_received: AtomicU64,
+    pub(crate) bytes_received: AtomicU64,
+    pub(crate) datagrams_sent: AtomicU64,
+    pub(crate) datagram_bytes_sent: AtomicU64,
+    pub(crate) datagrams_received: AtomicU64,
+    pub(crate) datagram_bytes_received: AtomicU64,
+    pub(crate) dropped_messages: AtomicU64,
+    paths: StdMutex<VecDeque<PathChange>>,
+}
+
+impl Counters {
+    /// Count one item of `bytes` on a message counter and its byte counter
+    pub(crate) fn record(count: &AtomicU64, total: &AtomicU64, bytes: usize) {
+        count.fetch_add(1, Ordering::Relaxed);
+        total.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_path(&self, connection_type: ConnectionType) {
+        let mut paths = self.paths.lock().unwrap();
+        if paths
+            .back()
+            .is_some_and(|last| last.connection_type == connection_type)
+        {
+            return;
+        }
+        if paths.len() == MAX_PATH_CHANGES {
+            paths.pop_front();
+        }
+        paths.push_back(PathChange {
+            at: Instant::now(),
+            connection_type,
+        });
+    }
+
+    pub(crate) fn current_path(&self) -> ConnectionType {
+        let paths = self.paths.lock().unwrap();
+        paths
+            .back()
+            .map(|path| path.connection_type.clone())
+            .unwrap_or(ConnectionType::None)
+    }
+
+    /// Combine our counters with the figures of the QUIC layer
+    pub(crate) fn snapshot(
+        &self,
+        quic: &iroh::endpoint::ConnectionStats,
+        send_queue_depth: usize,
+        dropped_sends: u64,
+        heartbeat_rtt: Option<RttEstimate>,
+    ) -> ConnectionStats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let paths = self.paths.lock().unwrap();
+        ConnectionStats {
+            messages_sent: load(&self.messages_sent),
+            bytes_sent: load(&self.bytes_sent),
+            messages_received: load(&self.messages_received),
+            bytes_received: load(&self.bytes_received),
+            datagrams_sent: load(&self.datagrams_sent),
+            datagram_bytes_sent: load(&self.datagram_bytes_sent),
+            datagrams_received: load(&self.datagrams_received),
+            datagram_bytes_received: load(&self.datagram_bytes_received),
+            dropped_messages: load(&self.dropped_messages),
+            send_queue_depth,
+            dropped_sends,
+            rtt: quic.path.rtt,
+            heartbeat_rtt,
+            congestion_window: quic.path.cwnd,
+            sent_packets: quic.path.sent_packets,
+            lost_packets: quic.path.lost_packets,
+            congestion_events: quic.path.congestion_events,
+            connection_type: paths
+                .back()
+                .map(|path| path.connection_type.clone())
+                .unwrap_or(ConnectionType::None),
+            path_changes: paths.iter().cloned().collect(),
+        }
+    }
+}
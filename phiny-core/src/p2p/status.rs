@@ -0,0 +1,99 @@
+use iroh::endpoint::ConnectionError;
+use tokio::sync::watch;
+
+use crate::p2p::close::CloseCode;
+
+/// Lifecycle state of a [`Connection`](crate::p2p::Connection)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    /// The connection is up and keeping up with the traffic
+    Connected,
+    /// The connection is up but struggling, e.g. the network does not keep up with our sends
+    Degraded { reason: String },
+    /// The connection was closed on purpose by either side
+    Closed {
+        code: CloseCode,
+        reason: String,
+        by_remote: bool,
+    },
+    /// The connection failed because of a transport error
+    Error(String),
+}
+
+impl ConnectionStatus {
+    /// Whether the connection is gone for good
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            ConnectionStatus::Closed { .. } | ConnectionStatus::Error(_)
+        )
+    }
+
+    pub(crate) fn from_connection_error(error: &ConnectionError) -> Self {
+        match error {
+            ConnectionError::ApplicationClosed(close) => ConnectionStatus::Closed {
+                code: CloseCode::from_varint(close.error_code),
+                reason: String::from_utf8_lossy(&close.reason).into_owned(),
+                by_remote: true,
+            },
+            ConnectionError::LocallyClosed => ConnectionStatus::Closed {
+                code: CloseCode::Normal,
+                reason: "closed locally".to_string(),
+                by_remote: false,
+            },
+            error => ConnectionStatus::Error(error.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for ConnectionStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionStatus::Connected => write!(f, "connected"),
+            ConnectionStatus::Degraded { reason } => write!(f, "degraded: {}", reason),
+            ConnectionStatus::Closed {
+                code,
+                reason,
+                by_remote,
+            } => {
+                let side = if *by_remote { "peer" } else { "we" };
+                write!(f, "{} closed the connection ({})", side, code)?;
+                if !reason.is_empty() {
+                    write!(f, ": {}", reason)?;
+                }
+                Ok(())
+            }
+            ConnectionStatus::Error(e) => write!(f, "connection error: {}", e),
+        }
+    }
+}
+
+/// Updates the status shared between a connection and its background tasks
+pub(crate) trait StatusExt {
+    /// Move to a terminal status, unless the connection already ended
+    fn terminate(&self, status: ConnectionStatus);
+    /// Move between live statuses, ignored once the connection ended
+    fn set_live(&self, to: ConnectionStatus);
+}
+
+impl StatusExt for watch::Sender<ConnectionStatus> {
+    fn terminate(&self, status: ConnectionStatus) {
+        self.send_if_modified(|current| {
+            if current.is_terminal() {
+                return false;
+            }
+            *current = status;
+            true
+        });
+    }
+
+    fn set_live(&self, to: ConnectionStatus) {
+        self.send_if_modified(|current| {
+            if current.is_terminal() || *current == to {
+                return false;
+            }
+            *current = to;
+            true
+        });
+    }
+}
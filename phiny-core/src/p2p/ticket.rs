@@ -1,27 +1,492 @@
-use data_encoding::BASE32;
-use iroh::NodeAddr;
-use serde::{Deserialize, Serialize};
+use std::{
+    net::SocketAddr,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-#[derive(Debug, Serialize, Deserialize)]
+use bincode::{Decode, Encode};
+use data_encoding::{BASE32, BASE32_DNSSEC};
+use iroh::{NodeAddr, NodeId, RelayUrl, SecretKey};
+use iroh_base::Signature;
+use serde::Deserialize;
+
+use crate::p2p::{handshake::Capabilities, invite::InviteToken};
+
+/// Every ticket in the current format starts with this
+pub const TICKET_PREFIX: &str = "phiny:";
+
+// Version of the binary layout, bumped on incompatible changes
+const TICKET_VERSION: u8 = 1;
+
+// Bytes of the blake3 hash appended to detect typos
+const CHECKSUM_SIZE: usize = 4;
+
+/// What is wrong with a ticket which could not be decoded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TicketError {
+    /// Neither a `phiny:` ticket nor a legacy one
+    UnknownFormat,
+    /// The ticket contains characters outside of its alphabet
+    InvalidEncoding,
+    /// The ticket is too short to hold a version and a checksum
+    Truncated,
+    /// The ticket was made by a newer (or unknown) version of phiny
+    UnsupportedVersion(u8),
+    /// The checksum does not match, the ticket was most likely mistyped or cut
+    ChecksumMismatch,
+    /// The ticket decodes but its content makes no sense
+    Malformed(String),
+    /// The ticket is past its expiry time
+    Expired,
+    /// The ticket carries no signature
+    Unsigned,
+    /// The signature was not made by the ticket's node, the ticket was forged or altered
+    InvalidSignature,
+}
+
+impl std::fmt::Display for TicketError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TicketError::UnknownFormat => {
+                write!(
+                    f,
+                    "not a phiny ticket, it should start with {}",
+                    TICKET_PREFIX
+                )
+            }
+            TicketError::InvalidEncoding => write!(f, "ticket contains invalid characters"),
+            TicketError::Truncated => write!(f, "ticket is too short, was it cut?"),
+            TicketError::UnsupportedVersion(version) => write!(
+                f,
+                "ticket version {} is not supported (expected {})",
+                version, TICKET_VERSION
+            ),
+            TicketError::ChecksumMismatch => {
+                write!(f, "ticket checksum does not match, check for typos")
+            }
+            TicketError::Malformed(reason) => write!(f, "ticket is malformed: {}", reason),
+            TicketError::Expired => write!(f, "ticket has expired"),
+            TicketError::Unsigned => write!(f, "ticket is not signed"),
+            TicketError::InvalidSignature => write!(f, "ticket signature is not valid"),
+        }
+    }
+}
+
+impl std::error::Error for TicketError {}
+
+// Binary layout of a ticket, following the version byte
+#[derive(Encode, Decode)]
+struct TicketBody {
+    node_id: [u8; 32],
+    relay_url: Option<String>,
+    direct_addresses: Vec<SocketAddr>,
+    display_name: Option<String>,
+    call_id: Option<String>,
+    codec_profile: Option<Capabilities>,
+    created_at: Option<u64>,
+    expires_at: Option<u64>,
+    invite: Option<InviteToken>,
+    signature: Option<[u8; 64]>,
+}
+
+// What the signature covers, addresses are left out as they change while the issuer runs
+#[derive(Encode)]
+struct SignedFields<'a> {
+    node_id: &'a [u8; 32],
+    display_name: &'a Option<String>,
+    call_id: &'a Option<String>,
+    codec_profile: &'a Option<Capabilities>,
+    created_at: Option<u64>,
+    expires_at: Option<u64>,
+    invite: Option<&'a InviteToken>,
+}
+
+// Tickets from before the binary format were base32 of this, serialized as JSON
+#[derive(Deserialize)]
+struct LegacyTicket {
+    node_addrs: NodeAddr,
+}
+
+/// Everything needed to call a peer, shared as a `phiny:` string
+///
+/// The string is `phiny:` followed by lowercase base32 of a version byte, the address of the
+/// peer, optional call metadata and a checksum. Legacy tickets (base32 of JSON) are still
+/// accepted by [`Ticket::decode`].
+///
+/// A ticket signed with [`Ticket::sign`] proves the metadata comes from the peer it points to;
+/// the connector presents it during the handshake, so a listener with
+/// [`PeerConfig::require_ticket`](crate::p2p::PeerConfig::require_ticket) turns away expired
+/// or forged invitations.
+#[derive(Debug, Clone)]
 pub struct Ticket {
     pub node_addrs: NodeAddr,
+    /// Name of the issuing peer, to show who is being called
+    pub display_name: Option<String>,
+    /// Room or call the ticket invites to
+    pub call_id: Option<String>,
+    /// Audio settings the issuer asks for
+    pub codec_profile: Option<Capabilities>,
+    /// Unix time in seconds the ticket was issued at
+    pub created_at: Option<u64>,
+    /// Unix time in seconds after which the ticket is no longer valid
+    pub expires_at: Option<u64>,
+    /// Secret letting a limited number of peers in, see [`Peer::invite`](crate::p2p::Peer::invite)
+    pub invite: Option<InviteToken>,
+    signature: Option<[u8; 64]>,
 }
 
 impl Ticket {
     pub fn new(node_addr: NodeAddr) -> Self {
         Self {
             node_addrs: node_addr,
+            display_name: None,
+            call_id: None,
+            codec_profile: None,
+            created_at: None,
+            expires_at: None,
+            invite: None,
+            signature: None,
+        }
+    }
+
+    pub fn with_display_name(mut self, display_name: impl Into<String>) -> Self {
+        self.display_name = Some(display_name.into());
+        self
+    }
+
+    pub fn with_call_id(mut self, call_id: impl Into<String>) -> Self {
+        self.call_id = Some(call_id.into());
+        self
+    }
+
+    pub fn with_codec_profile(mut self, codec_profile: Capabilities) -> Self {
+        self.codec_profile = Some(codec_profile);
+        self
+    }
+
+    /// Issue the ticket now, valid for `ttl`
+    pub fn valid_for(mut self, ttl: Duration) -> Self {
+        let now = unix_time();
+        self.created_at = Some(now);
+        self.expires_at = Some(now.saturating_add(ttl.as_secs()));
+        self
+    }
+
+    /// Sign the metadata with the key of the ticket's node, changing it afterwards voids the
+    /// signature
+    pub fn sign(mut self, secret_key: &SecretKey) -> Result<Self, TicketError> {
+        if secret_key.public() != self.node_addrs.node_id {
+            return Err(TicketError::Malformed(
+                "signing key does not belong to the ticket's node".to_string(),
+            ));
         }
+        self.created_at.get_or_insert_with(unix_time);
+        self.signature = Some(secret_key.sign(&self.signed_bytes()?).to_bytes());
+        Ok(self)
+    }
+
+    pub fn is_signed(&self) -> bool {
+        self.signature.is_some()
+    }
+
+    /// Check the signature was made by the ticket's node over the current metadata
+    pub fn verify_signature(&self) -> Result<(), TicketError> {
+        let signature = self.signature.ok_or(TicketError::Unsigned)?;
+        self.node_addrs
+            .node_id
+            .verify(&self.signed_bytes()?, &Signature::from_bytes(&signature))
+            .map_err(|_| TicketError::InvalidSignature)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| unix_time() >= expires_at)
+    }
+
+    /// Check the ticket can still be used: not expired and, if signed, genuine
+    pub fn validate(&self) -> Result<(), TicketError> {
+        if self.is_signed() {
+            self.verify_signature()?;
+        }
+        if self.is_expired() {
+            return Err(TicketError::Expired);
+        }
+        Ok(())
+    }
+
+    fn signed_bytes(&self) -> Result<Vec<u8>, TicketError> {
+        let fields = SignedFields {
+            node_id: self.node_addrs.node_id.as_bytes(),
+            display_name: &self.display_name,
+            call_id: &self.call_id,
+            codec_profile: &self.codec_profile,
+            created_at: self.created_at,
+            expires_at: self.expires_at,
+            invite: self.invite.as_ref(),
+        };
+        bincode::encode_to_vec(&fields, bincode::config::standard())
+            .map_err(|e| TicketError::Malformed(e.to_string()))
+    }
+
+    /// The same ticket without the relay, so the peer is only dialed at its direct addresses
+    pub fn direct_only(mut self) -> Self {
+        self.node_addrs.relay_url = None;
+        self
+    }
+
+    /// The same ticket without direct addresses, which makes it much shorter; the peer is then
+    /// reached through its relay or discovery
+    pub fn without_direct_addresses(mut self) -> Self {
+        self.node_addrs.direct_addresses.clear();
+        self
     }
 
     pub fn encode(&self) -> anyhow::Result<String> {
-        let jsonified_self = serde_json::to_string(self)?;
-        let base32_jsonified_self = BASE32.encode(jsonified_self.as_bytes());
-        return Ok(base32_jsonified_self);
-    }
-    pub fn decode(encoded_data: &str) -> anyhow::Result<Self> {
-        let base32_decoded = BASE32.decode(encoded_data.as_bytes())?;
-        let decoded_self = serde_json::from_slice(&base32_decoded)?;
-        return Ok(decoded_self);
+        let body = TicketBody {
+            node_id: *self.node_addrs.node_id.as_bytes(),
+            relay_url: self
+                .node_addrs
+                .relay_url
+                .as_ref()
+                .map(|url| url.to_string()),
+            direct_addresses: self.node_addrs.direct_addresses.iter().copied().collect(),
+            display_name: self.display_name.clone(),
+            call_id: self.call_id.clone(),
+            codec_profile: self.codec_profile.clone(),
+            created_at: self.created_at,
+            expires_at: self.expires_at,
+            invite: self.invite,
+            signature: self.signature,
+        };
+
+        let mut data = vec![TICKET_VERSION];
+        data.extend(bincode::encode_to_vec(&body, bincode::config::standard())?);
+        let checksum = blake3::hash(&data);
+        data.extend_from_slice(&checksum.as_bytes()[..CHECKSUM_SIZE]);
+
+        Ok(format!("{}{}", TICKET_PREFIX, BASE32_DNSSEC.encode(&data)))
+    }
+
+    pub fn decode(encoded_data: &str) -> Result<Self, TicketError> {
+        let encoded_data = encoded_data.trim();
+        let Some(encoded) = strip_prefix(encoded_data) else {
+            return Self::decode_legacy(encoded_data);
+        };
+
+        let data = BASE32_DNSSEC
+            .decode(encoded.to_ascii_lowercase().as_bytes())
+            .map_err(|_| TicketError::InvalidEncoding)?;
+        if data.len() < 1 + CHECKSUM_SIZE {
+            return Err(TicketError::Truncated);
+        }
+
+        // A typo may hit the version byte too, so the checksum speaks first
+        let (data, checksum) = data.split_at(data.len() - CHECKSUM_SIZE);
+        if blake3::hash(data).as_bytes()[..CHECKSUM_SIZE] != *checksum {
+            return Err(TicketError::ChecksumMismatch);
+        }
+        if data[0] != TICKET_VERSION {
+            return Err(TicketError::UnsupportedVersion(data[0]));
+        }
+
+        let (body, _): (TicketBody, _) =
+            bincode::decode_from_slice(&data[1..], bincode::config::standard())
+                .map_err(|e| TicketError::Malformed(e.to_string()))?;
+
+        let node_id = NodeId::from_bytes(&body.node_id)
+            .map_err(|e| TicketError::Malformed(format!("invalid node id: {}", e)))?;
+        let mut node_addr = NodeAddr::new(node_id).with_direct_addresses(body.direct_addresses);
+        if let Some(relay_url) = body.relay_url {
+            let relay_url = RelayUrl::from_str(&relay_url)
+                .map_err(|e| TicketError::Malformed(format!("invalid relay url: {}", e)))?;
+            node_addr = node_addr.with_relay_url(relay_url);
+        }
+        Ok(Self {
+            node_addrs: node_addr,
+            display_name: body.display_name,
+            call_id: body.call_id,
+            codec_profile: body.codec_profile,
+            created_at: body.created_at,
+            expires_at: body.expires_at,
+            invite: body.invite,
+            signature: body.signature,
+        })
+    }
+
+    // Tickets from before the binary format: base32 of the JSON serialized ticket
+    fn decode_legacy(encoded_data: &str) -> Result<Self, TicketError> {
+        let base32_decoded = BASE32
+            .decode(encoded_data.as_bytes())
+            .map_err(|_| TicketError::UnknownFormat)?;
+        let legacy: LegacyTicket = serde_json::from_slice(&base32_decoded)
+            .map_err(|e| TicketError::Malformed(format!("invalid legacy ticket: {}", e)))?;
+        Ok(Self::new(legacy.node_addrs))
+    }
+}
+
+pub(crate) fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// The prefix is matched case insensitively, tickets may go through tools changing the case
+fn strip_prefix(ticket: &str) -> Option<&str> {
+    let prefix = ticket.get(..TICKET_PREFIX.len())?;
+    prefix
+        .eq_ignore_ascii_case(TICKET_PREFIX)
+        .then(|| &ticket[TICKET_PREFIX.len()..])
+}
+
+impl FromStr for Ticket {
+    type Err = TicketError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::decode(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secret_key() -> SecretKey {
+        SecretKey::from_bytes(&[7; 32])
+    }
+
+    fn node_addr() -> NodeAddr {
+        NodeAddr::new(secret_key().public())
+            .with_relay_url(RelayUrl::from_str("https://relay.example.org").unwrap())
+            .with_direct_addresses(["192.0.2.1:4433".parse().unwrap()])
+    }
+
+    // A `phiny:` ticket of `data` with a valid checksum
+    fn with_checksum(mut data: Vec<u8>) -> String {
+        let checksum = blake3::hash(&data);
+        data.extend_from_slice(&checksum.as_bytes()[..CHECKSUM_SIZE]);
+        format!("{}{}", TICKET_PREFIX, BASE32_DNSSEC.encode(&data))
+    }
+
+    fn raw(ticket: &str) -> Vec<u8> {
+        BASE32_DNSSEC
+            .decode(strip_prefix(ticket).unwrap().as_bytes())
+            .unwrap()
+    }
+
+    #[test]
+    fn round_trip_keeps_everything() {
+        let mut ticket = Ticket::new(node_addr())
+            .with_display_name("alice")
+            .with_call_id("standup")
+            .with_codec_profile(Capabilities::default())
+            .valid_for(Duration::from_secs(60));
+        ticket.invite = Some(InviteToken::new());
+        let ticket = ticket.sign(&secret_key()).unwrap();
+
+        let decoded = Ticket::decode(&ticket.encode().unwrap()).unwrap();
+        assert_eq!(decoded.node_addrs, ticket.node_addrs);
+        assert_eq!(decoded.display_name, ticket.display_name);
+        assert_eq!(decoded.call_id, ticket.call_id);
+        assert_eq!(decoded.created_at, ticket.created_at);
+        assert_eq!(decoded.expires_at, ticket.expires_at);
+        assert_eq!(decoded.invite, ticket.invite);
+        assert!(decoded.codec_profile.is_some());
+        decoded.validate().unwrap();
+    }
+
+    #[test]
+    fn round_trip_ignores_case_and_whitespace() {
+        let ticket = Ticket::new(node_addr()).without_direct_addresses();
+        let encoded = format!("  {}\n", ticket.encode().unwrap().to_ascii_uppercase());
+        let decoded = Ticket::decode(&encoded).unwrap();
+        assert_eq!(decoded.node_addrs, ticket.node_addrs);
+        assert!(!decoded.is_signed());
+    }
+
+    #[test]
+    fn altered_ticket_fails_the_checksum() {
+        let mut data = raw(&Ticket::new(node_addr()).encode().unwrap());
+        let middle = data.len() / 2;
+        data[middle] ^= 1;
+        let altered = format!("{}{}", TICKET_PREFIX, BASE32_DNSSEC.encode(&data));
+        assert_eq!(
+            Ticket::decode(&altered).unwrap_err(),
+            TicketError::ChecksumMismatch
+        );
+    }
+
+    #[test]
+    fn checksum_is_checked_before_the_version() {
+        let mut data = raw(&Ticket::new(node_addr()).encode().unwrap());
+        data[0] = u8::MAX;
+        let altered = format!("{}{}", TICKET_PREFIX, BASE32_DNSSEC.encode(&data));
+        assert_eq!(
+            Ticket::decode(&altered).unwrap_err(),
+            TicketError::ChecksumMismatch
+        );
+    }
+
+    #[test]
+    fn unknown_version_is_unsupported() {
+        let mut data = raw(&Ticket::new(node_addr()).encode().unwrap());
+        data.truncate(data.len() - CHECKSUM_SIZE);
+        data[0] = u8::MAX;
+        assert_eq!(
+            Ticket::decode(&with_checksum(data)).unwrap_err(),
+            TicketError::UnsupportedVersion(u8::MAX)
+        );
+    }
+
+    #[test]
+    fn cut_tickets_are_truncated_or_malformed() {
+        let short = format!(
+            "{}{}",
+            TICKET_PREFIX,
+            BASE32_DNSSEC.encode(&[TICKET_VERSION, 0])
+        );
+        assert_eq!(Ticket::decode(&short).unwrap_err(), TicketError::Truncated);
+
+        let mut data = raw(&Ticket::new(node_addr()).encode().unwrap());
+        data.truncate(10);
+        assert!(matches!(
+            Ticket::decode(&with_checksum(data)).unwrap_err(),
+            TicketError::Malformed(_)
+        ));
+    }
+
+    #[test]
+    fn invalid_characters_are_rejected() {
+        let ticket = format!("{}not base32!", TICKET_PREFIX);
+        assert_eq!(
+            Ticket::decode(&ticket).unwrap_err(),
+            TicketError::InvalidEncoding
+        );
+    }
+
+    #[test]
+    fn legacy_json_tickets_are_accepted() {
+        let json = serde_json::json!({ "node_addrs": node_addr() });
+        let legacy = BASE32.encode(json.to_string().as_bytes());
+        let decoded = Ticket::decode(&legacy).unwrap();
+        assert_eq!(decoded.node_addrs, node_addr());
+        assert!(decoded.display_name.is_none());
+
+        assert_eq!(
+            Ticket::decode("hello").unwrap_err(),
+            TicketError::UnknownFormat
+        );
+        assert!(matches!(
+            Ticket::decode(&BASE32.encode(b"{}")).unwrap_err(),
+            TicketError::Malformed(_)
+        ));
+    }
+
+    #[test]
+    fn changed_metadata_voids_the_signature() {
+        let mut ticket = Ticket::new(node_addr()).sign(&secret_key()).unwrap();
+        ticket.display_name = Some("mallory".to_string());
+        let decoded = Ticket::decode(&ticket.encode().unwrap()).unwrap();
+        assert_eq!(decoded.validate(), Err(TicketError::InvalidSignature));
     }
 }
@@ -0,0 +1,506 @@
+use std::{
+    io::SeekFrom,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context as _, Result, anyhow, bail};
+use bincode::{Decode, Encode};
+use iroh::endpoint::{ReadError, RecvStream, SendStream, VarInt, WriteError};
+use log::debug;
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    select,
+    sync::watch,
+    task::JoinHandle,
+};
+
+use crate::p2p::connection::Connection;
+
+// File data is read, sent and written in chunks of this size
+const CHUNK_SIZE: usize = 64 * 1024;
+// Largest header frame accepted on a transfer stream
+const MAX_HEADER_SIZE: usize = 16 * 1024;
+// Stream error code telling the other side the transfer was cancelled
+const CANCELLED: u32 = 1;
+// Below the message stream, so file data never delays signaling; audio goes in datagrams
+const STREAM_PRIORITY: i32 = -1;
+
+/// Messages exchanged on the stream of a file transfer, the file data follows the answer
+#[derive(Debug, Encode, Decode)]
+enum TransferMessage {
+    Offer {
+        name: String,
+        size: u64,
+        hash: [u8; 32],
+    },
+    /// Send the file from `offset` on, the receiver kept the bytes before
+    Accept {
+        offset: u64,
+    },
+    Reject {
+        reason: String,
+    },
+    /// The whole file arrived and matches its hash
+    Verified,
+    /// The file arrived but does not match its hash, the received data was dropped
+    Corrupted,
+}
+
+/// What the sender announces about a file before sending it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileOffer {
+    /// File name without any directory
+    pub name: String,
+    pub size: u64,
+    /// BLAKE3 hash of the whole file
+    pub hash: [u8; 32],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TransferProgress {
+    /// Bytes the receiver holds, including the ones kept from an earlier attempt
+    pub transferred: u64,
+    pub size: u64,
+}
+
+/// A file being sent or received on a stream of its own, next to the messages and datagrams
+/// of the connection
+///
+/// An interrupted or cancelled transfer can be resumed by offering the same file again: the
+/// receiver keeps the bytes it got and only asks for the rest. Dropping the handle cancels the
+/// transfer.
+pub struct FileTransfer {
+    offer: FileOffer,
+    progress: watch::Receiver<TransferProgress>,
+    cancel: watch::Sender<bool>,
+    task: JoinHandle<Result<PathBuf>>,
+}
+
+impl FileTransfer {
+    /// Offer a file to the peer, which takes it with [`IncomingFile::accept`]
+    pub async fn send(connection: &Connection, path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| anyhow!("{} has no file name", path.display()))?
+            .to_string();
+        let size = fs::metadata(&path)
+            .await
+            .with_context(|| format!("Failed to read {}", path.display()))?
+            .len();
+        let offer = FileOffer {
+            name,
+            size,
+            hash: *hash_file(&path).await?.as_bytes(),
+        };
+
+        let (mut send, recv) = connection.open_stream().await?;
+        let _ = send.set_priority(STREAM_PRIORITY);
+        write_message(
+            &mut send,
+            &TransferMessage::Offer {
+                name: offer.name.clone(),
+                size: offer.size,
+                hash: offer.hash,
+            },
+        )
+        .await?;
+
+        Ok(Self::spawn(offer, 0, |progress, cancel| {
+            send_file(path, size, send, recv, progress, cancel)
+        }))
+    }
+
+    // `transferred` are the bytes the receiver already holds, when known
+    fn spawn<F>(
+        offer: FileOffer,
+        transferred: u64,
+        run: impl FnOnce(watch::Sender<TransferProgress>, watch::Receiver<bool>) -> F,
+    ) -> Self
+    where
+        F: Future<Output = Result<PathBuf>> + Send + 'static,
+    {
+        let (progress_tx, progress) = watch::channel(TransferProgress {
+            transferred,
+            size: offer.size,
+        });
+        let (cancel, cancel_rx) = watch::channel(false);
+        let task = tokio::spawn(run(progress_tx, cancel_rx));
+        FileTransfer {
+            offer,
+            progress,
+            cancel,
+            task,
+        }
+    }
+
+    pub fn offer(&self) -> &FileOffer {
+        &self.offer
+    }
+
+    pub fn progress(&self) -> TransferProgress {
+        *self.progress.borrow()
+    }
+
+    /// Watch the progress of the transfer as it changes
+    pub fn watch_progress(&self) -> watch::Receiver<TransferProgress> {
+        self.progress.clone()
+    }
+
+    /// Stop the transfer, the peer is told and the bytes received so far are kept for a resume
+    pub fn cancel(&self) {
+        self.cancel.send_replace(true);
+    }
+
+    /// Wait for the transfer to end; returns the sent file, or where the received one was saved
+    /// once its hash was verified
+    pub async fn finished(mut self) -> Result<PathBuf> {
+        (&mut self.task).await?
+    }
+}
+
+impl Drop for FileTransfer {
+    fn drop(&mut self) {
+        // The task resets the stream, aborting it would let quinn finish the stream and the
+        // peer take a cut file for a complete one
+        self.cancel.send_replace(true);
+    }
+}
+
+/// A file the peer offered, to accept or reject
+pub struct IncomingFile {
+    offer: FileOffer,
+    send: SendStream,
+    recv: RecvStream,
+}
+
+impl IncomingFile {
+    /// Wait for the peer to offer a file with [`FileTransfer::send`]
+    pub async fn receive(connection: &Connection) -> Result<Self> {
+        let (send, mut recv) = connection.accept_stream().await?;
+        match read_message(&mut recv).await? {
+            TransferMessage::Offer { name, size, hash } => Ok(IncomingFile {
+                offer: FileOffer { name, size, hash },
+                send,
+                recv,
+            }),
+            other => bail!("Expected a file offer, got {:?}", other),
+        }
+    }
+
+    pub fn offer(&self) -> &FileOffer {
+        &self.offer
+    }
+
+    /// Receive the file into `dir`, resuming an earlier transfer of the same file
+    ///
+    /// The data is written to a `.part` file named after the hash and renamed to the offered
+    /// name (made unique within `dir`) once it is verified.
+    pub async fn accept(self, dir: impl AsRef<Path>) -> Result<FileTransfer> {
+        let IncomingFile {
+            offer,
+            mut send,
+            recv,
+        } = self;
+        let dir = dir.as_ref().to_path_buf();
+        let name = match Path::new(&offer.name).file_name() {
+            Some(name) if name.to_str() == Some(offer.name.as_str()) => offer.name.clone(),
+            _ => {
+                let reason = "invalid file name";
+                let _ = write_message(
+                    &mut send,
+                    &TransferMessage::Reject {
+                        reason: reason.to_string(),
+                    },
+                )
+                .await;
+                bail!("Peer offered a file with an {}: {:?}", reason, offer.name);
+            }
+        };
+        fs::create_dir_all(&dir)
+            .await
+            .with_context(|| format!("Failed to create the directory {}", dir.display()))?;
+
+        let part = dir.join(format!(
+            "{}.part",
+            data_encoding::HEXLOWER.encode(&offer.hash)
+        ));
+        let offset = match fs::metadata(&part).await {
+            Ok(metadata) if metadata.len() <= offer.size => metadata.len(),
+            _ => 0,
+        };
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(offset == 0)
+            .open(&part)
+            .await
+            .with_context(|| format!("Failed to create {}", part.display()))?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        write_message(&mut send, &TransferMessage::Accept { offset }).await?;
+
+        let size = offer.size;
+        let hash = offer.hash;
+        Ok(FileTransfer::spawn(
+            offer,
+            offset,
+            move |progress, cancel| {
+                receive_file(
+                    Destination {
+                        file,
+                        part,
+                        dir,
+                        name,
+                        size,
+                        hash,
+                        offset,
+                    },
+                    send,
+                    recv,
+                    progress,
+                    cancel,
+                )
+            },
+        ))
+    }
+
+    /// Decline the file
+    pub async fn reject(mut self, reason: &str) -> Result<()> {
+        write_message(
+            &mut self.send,
+            &TransferMessage::Reject {
+                reason: reason.to_string(),
+            },
+        )
+        .await?;
+        let _ = self.send.finish();
+        Ok(())
+    }
+}
+
+async fn send_file(
+    path: PathBuf,
+    size: u64,
+    mut send: SendStream,
+    mut recv: RecvStream,
+    progress: watch::Sender<TransferProgress>,
+    mut cancel: watch::Receiver<bool>,
+) -> Result<PathBuf> {
+    let answer = select! {
+        answer = read_message(&mut recv) => answer?,
+        _ = cancel.wait_for(|cancel| *cancel) => {
+            let _ = send.reset(VarInt::from_u32(CANCELLED));
+            bail!("Transfer cancelled");
+        }
+    };
+    let offset = match answer {
+        TransferMessage::Accept { offset } if offset <= size => offset,
+        TransferMessage::Reject { reason } => bail!("Peer declined the file: {}", reason),
+        other => bail!("Expected an answer to the file offer, got {:?}", other),
+    };
+
+    let mut file = File::open(&path)
+        .await
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    let mut transferred = offset;
+    progress.send_replace(TransferProgress { transferred, size });
+
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    while transferred < size {
+        let len = file.read(&mut buffer).await?;
+        if len == 0 {
+            break;
+        }
+        let len = len.min((size - transferred) as usize);
+        select! {
+            written = send.write_all(&buffer[..len]) => match written {
+                Ok(()) => {}
+                Err(WriteError::Stopped(code)) if code == VarInt::from_u32(CANCELLED) => {
+                    bail!("Peer cancelled the transfer")
+                }
+                Err(e) => return Err(e).context("Failed to send the file"),
+            },
+            _ = cancel.wait_for(|cancel| *cancel) => {
+                let _ = send.reset(VarInt::from_u32(CANCELLED));
+                bail!("Transfer cancelled");
+            }
+        }
+        transferred += len as u64;
+        progress.send_replace(TransferProgress { transferred, size });
+    }
+    if transferred < size {
+        let _ = send.reset(VarInt::from_u32(CANCELLED));
+        bail!("{} shrank while it was being sent", path.display());
+    }
+    send.finish()?;
+
+    match read_message(&mut recv).await? {
+        TransferMessage::Verified => Ok(path),
+        TransferMessage::Corrupted => bail!("Peer received a corrupted copy of the file"),
+        other => bail!("Expected the peer to verify the file, got {:?}", other),
+    }
+}
+
+// Where a received file goes
+struct Destination {
+    file: File,
+    part: PathBuf,
+    dir: PathBuf,
+    name: String,
+    size: u64,
+    hash: [u8; 32],
+    /// Bytes kept from an earlier attempt
+    offset: u64,
+}
+
+async fn receive_file(
+    destination: Destination,
+    mut send: SendStream,
+    mut recv: RecvStream,
+    progress: watch::Sender<TransferProgress>,
+    mut cancel: watch::Receiver<bool>,
+) -> Result<PathBuf> {
+    let Destination {
+        mut file,
+        part,
+        dir,
+        name,
+        size,
+        hash,
+        offset,
+    } = destination;
+    let mut transferred = offset;
+    progress.send_replace(TransferProgress { transferred, size });
+
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    loop {
+        let read = select! {
+            read = recv.read(&mut buffer) => Some(read),
+            _ = cancel.wait_for(|cancel| *cancel) => None,
+        };
+        let Some(read) = read else {
+            let _ = recv.stop(VarInt::from_u32(CANCELLED));
+            file.flush().await?;
+            bail!("Transfer cancelled after {} of {} bytes", transferred, size);
+        };
+        let len = match read {
+            Ok(Some(len)) => len,
+            Ok(None) => break,
+            Err(ReadError::Reset(code)) if code == VarInt::from_u32(CANCELLED) => {
+                file.flush().await?;
+                bail!(
+                    "Peer cancelled the transfer after {} of {} bytes",
+                    transferred,
+                    size
+                );
+            }
+            Err(e) => {
+                file.flush().await?;
+                return Err(e).context(format!(
+                    "Transfer interrupted after {} of {} bytes",
+                    transferred, size
+                ));
+            }
+        };
+        if transferred + len as u64 > size {
+            let _ = recv.stop(VarInt::from_u32(CANCELLED));
+            drop(file);
+            let _ = fs::remove_file(&part).await;
+            bail!("Peer sent more than the {} bytes it offered", size);
+        }
+        file.write_all(&buffer[..len]).await?;
+        transferred += len as u64;
+        progress.send_replace(TransferProgress { transferred, size });
+    }
+    file.flush().await?;
+    drop(file);
+    if transferred < size {
+        bail!(
+            "Transfer interrupted after {} of {} bytes",
+            transferred,
+            size
+        );
+    }
+
+    if *hash_file(&part).await?.as_bytes() != hash {
+        debug!("Dropping {}, it does not match its hash", part.display());
+        let _ = fs::remove_file(&part).await;
+        let _ = write_message(&mut send, &TransferMessage::Corrupted).await;
+        bail!("Received file does not match its hash");
+    }
+    let path = unused_path(&dir, &name);
+    fs::rename(&part, &path)
+        .await
+        .with_context(|| format!("Failed to move the file to {}", path.display()))?;
+    write_message(&mut send, &TransferMessage::Verified).await?;
+    let _ = send.finish();
+    let _ = send.stopped().await;
+    Ok(path)
+}
+
+async fn hash_file(path: &Path) -> Result<blake3::Hash> {
+    let mut file = File::open(path)
+        .await
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    loop {
+        let len = file.read(&mut buffer).await?;
+        if len == 0 {
+            return Ok(hasher.finalize());
+        }
+        hasher.update(&buffer[..len]);
+    }
+}
+
+// `name` in `dir`, or `name (1)`, `name (2)`... when a file of that name exists
+fn unused_path(dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(name);
+    if !path.exists() {
+        return path;
+    }
+    let name = Path::new(name);
+    let stem = name.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = name
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    (1..)
+        .map(|i| dir.join(format!("{} ({}){}", stem, i, extension)))
+        .find(|path| !path.exists())
+        .unwrap_or(path)
+}
+
+// Frame layout: length (u32 BE) | bincode payload
+async fn write_message(send: &mut SendStream, message: &TransferMessage) -> Result<()> {
+    let payload = bincode::encode_to_vec(message, bincode::config::standard())?;
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    send.write_all(&frame)
+        .await
+        .context("Failed to write on the transfer stream")
+}
+
+async fn read_message(recv: &mut RecvStream) -> Result<TransferMessage> {
+    let mut len = [0u8; 4];
+    recv.read_exact(&mut len)
+        .await
+        .context("Failed to read from the transfer stream")?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_HEADER_SIZE {
+        bail!(
+            "Transfer message of {} bytes exceeds the limit of {} bytes",
+            len,
+            MAX_HEADER_SIZE
+        );
+    }
+    let mut payload = vec![0u8; len];
+    recv.read_exact(&mut payload)
+        .await
+        .context("Failed to read from the transfer stream")?;
+    Ok(bincode::decode_from_slice(&payload, bincode::config::standard())?.0)
+}
@@ -0,0 +1,38 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use crate::p2p::{
+    connection::{Connection, Message},
+    session::Session,
+};
+
+// What a call or chat runs on, a session keeps it alive across reconnections
+#[derive(Clone)]
+pub(crate) enum Transport {
+    Connection(Arc<Connection>),
+    Session(Arc<Session>),
+}
+
+impl Transport {
+    pub(crate) fn connection(&self) -> Arc<Connection> {
+        match self {
+            Transport::Connection(connection) => Arc::clone(connection),
+            Transport::Session(session) => session.connection(),
+        }
+    }
+
+    pub(crate) async fn send<M: Message>(&self, message: M) -> Result<()> {
+        match self {
+            Transport::Connection(connection) => connection.send(message).await,
+            Transport::Session(session) => session.send(message).await,
+        }
+    }
+
+    pub(crate) async fn receive<M: Message>(&self) -> Result<Option<M>> {
+        match self {
+            Transport::Connection(connection) => connection.receive().await,
+            Transport::Session(session) => session.receive().await,
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use iroh::RelayMode;
+use phiny_core::p2p::{DiscoveryMode, Peer, PeerConfig};
+
+// A peer only reachable on the loopback interface: no relay, no discovery
+async fn local_peer(max_connections: usize) -> anyhow::Result<Peer> {
+    Peer::new(PeerConfig {
+        discovery: DiscoveryMode::None,
+        relay_mode: RelayMode::Disabled,
+        bind_addr_v4: Some(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)),
+        max_connections,
+        ..PeerConfig::default()
+    })
+    .await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn connector_over_the_limit_is_told_the_listener_is_busy() -> anyhow::Result<()> {
+    let (listener_peer, alice, bob) = (
+        local_peer(1).await?,
+        local_peer(1).await?,
+        local_peer(1).await?,
+    );
+    let mut listener = listener_peer.listen().await?;
+    let addr = listener_peer.reachable_address().await;
+
+    let (first, accepted) = tokio::join!(alice.connect(addr.clone()), listener.accept());
+    let _first = first?;
+    let _accepted = accepted?.expect("listener is open");
+
+    let Err(error) = bob.connect(addr).await else {
+        panic!("connected although every slot is taken");
+    };
+    assert!(
+        format!("{:#}", error).contains("busy"),
+        "unexpected error: {:#}",
+        error
+    );
+    assert_eq!(listener.stats().rejected_busy, 1);
+    Ok(())
+}
@@ -0,0 +1,133 @@
+use std::{
+    net::{Ipv4Addr, SocketAddrV4},
+    path::{Path, PathBuf},
+};
+
+use iroh::RelayMode;
+use phiny_core::p2p::{Connection, DiscoveryMode, FileTransfer, IncomingFile, Peer, PeerConfig};
+
+// A peer only reachable on the loopback interface: no relay, no discovery
+async fn local_peer() -> anyhow::Result<Peer> {
+    Peer::new(PeerConfig {
+        discovery: DiscoveryMode::None,
+        relay_mode: RelayMode::Disabled,
+        bind_addr_v4: Some(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)),
+        ..PeerConfig::default()
+    })
+    .await
+}
+
+// Both ends of a connection, the peers must outlive it
+async fn connected() -> anyhow::Result<(Peer, Peer, Connection, Connection)> {
+    let (alice, bob) = (local_peer().await?, local_peer().await?);
+    let mut listener = bob.listen().await?;
+    let (sender, receiver) = tokio::join!(
+        alice.connect(bob.reachable_address().await),
+        listener.accept()
+    );
+    Ok((alice, bob, sender?, receiver?.expect("listener is open")))
+}
+
+// An empty directory of its own for every test
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("phiny-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn content() -> Vec<u8> {
+    (0..300_000u32).map(|i| (i % 251) as u8).collect()
+}
+
+fn part_file(dir: &Path, data: &[u8]) -> PathBuf {
+    let hash = blake3::hash(data);
+    dir.join(format!(
+        "{}.part",
+        data_encoding::HEXLOWER.encode(hash.as_bytes())
+    ))
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn transfer_resumes_from_the_part_file() -> anyhow::Result<()> {
+    let dir = test_dir("resume");
+    let (source, downloads) = (dir.join("source"), dir.join("downloads"));
+    std::fs::create_dir_all(&source)?;
+    std::fs::create_dir_all(&downloads)?;
+    let data = content();
+    std::fs::write(source.join("notes.bin"), &data)?;
+    // An earlier attempt got the first part of the file
+    std::fs::write(part_file(&downloads, &data), &data[..100_000])?;
+
+    let (_alice, _bob, sender, receiver) = connected().await?;
+    let (sent, incoming) = tokio::join!(
+        FileTransfer::send(&sender, source.join("notes.bin")),
+        IncomingFile::receive(&receiver)
+    );
+    let (sent, received) = (sent?, incoming?.accept(&downloads).await?);
+    assert_eq!(received.progress().transferred, 100_000);
+
+    let (sent, received) = tokio::join!(sent.finished(), received.finished());
+    sent?;
+    let path = received?;
+    assert_eq!(path, downloads.join("notes.bin"));
+    assert_eq!(std::fs::read(&path)?, data);
+    assert!(!part_file(&downloads, &data).exists());
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn corrupted_part_file_is_dropped() -> anyhow::Result<()> {
+    let dir = test_dir("corrupted");
+    let (source, downloads) = (dir.join("source"), dir.join("downloads"));
+    std::fs::create_dir_all(&source)?;
+    std::fs::create_dir_all(&downloads)?;
+    let data = content();
+    std::fs::write(source.join("notes.bin"), &data)?;
+    // The kept bytes do not belong to the file, the result cannot match its hash
+    std::fs::write(part_file(&downloads, &data), vec![0xffu8; 100_000])?;
+
+    let (_alice, _bob, sender, receiver) = connected().await?;
+    let (sent, incoming) = tokio::join!(
+        FileTransfer::send(&sender, source.join("notes.bin")),
+        IncomingFile::receive(&receiver)
+    );
+    let (sent, received) = (sent?, incoming?.accept(&downloads).await?);
+
+    let (sent, received) = tokio::join!(sent.finished(), received.finished());
+    assert!(format!("{:#}", sent.unwrap_err()).contains("corrupted"));
+    assert!(format!("{:#}", received.unwrap_err()).contains("does not match its hash"));
+    assert!(!part_file(&downloads, &data).exists());
+    assert!(!downloads.join("notes.bin").exists());
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dropping_the_sender_cancels_the_transfer() -> anyhow::Result<()> {
+    let dir = test_dir("dropped");
+    let data = content();
+    std::fs::write(dir.join("notes.bin"), &data)?;
+
+    let (_alice, _bob, sender, receiver) = connected().await?;
+    let (sent, incoming) = tokio::join!(
+        FileTransfer::send(&sender, dir.join("notes.bin")),
+        IncomingFile::receive(&receiver)
+    );
+    drop(sent?);
+    let received = incoming?.accept(dir.join("downloads")).await?;
+
+    let error = received.finished().await.unwrap_err();
+    assert!(
+        format!("{:#}", error).contains("cancelled"),
+        "unexpected error: {:#}",
+        error
+    );
+    assert!(!dir.join("downloads").join("notes.bin").exists());
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
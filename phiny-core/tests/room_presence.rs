@@ -0,0 +1,83 @@
+use std::{
+    net::{Ipv4Addr, SocketAddrV4},
+    time::Duration,
+};
+
+use iroh::{NodeId, RelayMode};
+use phiny_core::p2p::{DiscoveryMode, Peer, PeerConfig, Room, RoomConfig};
+
+// Generous, gossip announces every few seconds
+const ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// A peer only reachable on the loopback interface: no relay, no discovery
+async fn local_peer(name: &str) -> anyhow::Result<Peer> {
+    Peer::new(PeerConfig {
+        discovery: DiscoveryMode::None,
+        relay_mode: RelayMode::Disabled,
+        bind_addr_v4: Some(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)),
+        gossip: true,
+        display_name: Some(name.to_string()),
+        ..PeerConfig::default()
+    })
+    .await
+}
+
+async fn wait_for_members(room: &Room, expected: &[NodeId]) {
+    let presence = room.presence().expect("gossip is enabled");
+    tokio::time::timeout(ANNOUNCE_TIMEOUT, async {
+        loop {
+            let members: Vec<_> = presence
+                .members()
+                .iter()
+                .map(|member| member.node_id)
+                .collect();
+            if expected.iter().all(|node_id| members.contains(node_id)) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .expect("members were not announced in time");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn local_members_find_each_other_through_presence() -> anyhow::Result<()> {
+    let (alice, bob, carol) = (
+        local_peer("alice").await?,
+        local_peer("bob").await?,
+        local_peer("carol").await?,
+    );
+
+    let alice_room = Room::create(
+        &alice,
+        alice.listen().await?,
+        "standup",
+        RoomConfig::default(),
+    )
+    .await?;
+    let bob_room = Room::join(
+        &bob,
+        bob.listen().await?,
+        &alice_room.invite_ticket(),
+        RoomConfig::default(),
+    )
+    .await?;
+    // Carol only knows the address of bob, she learns about alice on the topic
+    let carol_room = Room::join(
+        &carol,
+        carol.listen().await?,
+        &bob_room.invite_ticket(),
+        RoomConfig::default(),
+    )
+    .await?;
+
+    wait_for_members(&alice_room, &[bob.node_id(), carol.node_id()]).await;
+    wait_for_members(&bob_room, &[alice.node_id(), carol.node_id()]).await;
+    wait_for_members(&carol_room, &[alice.node_id(), bob.node_id()]).await;
+
+    carol_room.leave().await;
+    bob_room.leave().await;
+    alice_room.leave().await;
+    Ok(())
+}